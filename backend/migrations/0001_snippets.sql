@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS snippets (
+    slug        TEXT        NOT NULL,
+    content     TEXT        NOT NULL,
+    language    TEXT        NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at  TIMESTAMPTZ NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_snippets_slug    ON snippets (slug);
+CREATE        INDEX IF NOT EXISTS idx_snippets_expires ON snippets (expires_at);
+
+CREATE TABLE IF NOT EXISTS snippet_images (
+    seq     BIGSERIAL PRIMARY KEY,
+    slug    TEXT      NOT NULL REFERENCES snippets (slug) ON DELETE CASCADE,
+    id      TEXT      NOT NULL,
+    url     TEXT      NOT NULL,
+    width   INTEGER   NOT NULL,
+    height  INTEGER   NOT NULL,
+    UNIQUE (slug, id)
+);
+
+CREATE TABLE IF NOT EXISTS snippet_files (
+    seq     BIGSERIAL PRIMARY KEY,
+    slug    TEXT      NOT NULL REFERENCES snippets (slug) ON DELETE CASCADE,
+    id      TEXT      NOT NULL,
+    name    TEXT      NOT NULL,
+    url     TEXT      NOT NULL,
+    size    BIGINT    NOT NULL,
+    mime    TEXT      NOT NULL,
+    UNIQUE (slug, id)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE snippets
+    ADD COLUMN IF NOT EXISTS burn_after_reading BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS snippet_revisions (
+    slug        TEXT        NOT NULL REFERENCES snippets (slug) ON DELETE CASCADE,
+    rev         BIGINT      NOT NULL,
+    content     TEXT        NOT NULL,
+    language    TEXT        NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (slug, rev)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE snippets
+    ADD COLUMN IF NOT EXISTS crdt BYTEA;
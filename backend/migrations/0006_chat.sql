@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS snippet_chat (
+    seq         BIGSERIAL   PRIMARY KEY,
+    slug        TEXT        NOT NULL REFERENCES snippets (slug) ON DELETE CASCADE,
+    id          TEXT        NOT NULL,
+    conn        BIGINT      NOT NULL,
+    name        TEXT        NOT NULL,
+    color       TEXT        NOT NULL,
+    text        TEXT        NOT NULL,
+    sent_at     TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_snippet_chat_slug ON snippet_chat (slug, seq DESC);
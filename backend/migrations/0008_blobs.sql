@@ -0,0 +1,10 @@
+-- Uploads this backend handed out, and the snippet that claimed each one.
+-- Not tied to snippets by a foreign key: the row is deleted before its
+-- uploads, which are then looked up here by slug.
+CREATE TABLE IF NOT EXISTS blobs (
+    url         TEXT        PRIMARY KEY,
+    slug        TEXT,
+    issued_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_blobs_slug ON blobs (slug);
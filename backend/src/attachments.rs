@@ -0,0 +1,70 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json},
+};
+use std::sync::Arc;
+
+use crate::{claim_blobs, error::AppError, load_live, refuse_burn, room, AppState, FileData, ImageData, WsMsg};
+
+/// Answers an attach: 201 when it went in, 200 when it was already there.
+fn attached<T: serde::Serialize>(added: bool, item: T) -> impl IntoResponse {
+    let status = if added { StatusCode::CREATED } else { StatusCode::OK };
+    (status, Json(item))
+}
+
+// ── Handlers ──────────────────────────────────────────────────────────────────
+
+/// `POST /api/snippets/:slug/images` with an `ImageData` from `/api/upload`.
+pub async fn attach_image(
+    State(s): State<Arc<AppState>>,
+    Path(slug): Path<String>,
+    Json(image): Json<ImageData>,
+) -> Result<impl IntoResponse, AppError> {
+    refuse_burn(&load_live(&s, &slug).await?)?;
+    claim_blobs(&s, &slug, &[&image.url]).await?;
+    let added = s.store.add_image(&slug, image.clone()).await?;
+    if added {
+        room::broadcast(&s, &slug, WsMsg::BroadcastImage { image: image.clone() });
+    }
+    Ok(attached(added, image))
+}
+
+pub async fn detach_image(
+    State(s): State<Arc<AppState>>,
+    Path((slug, id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    load_live(&s, &slug).await?;
+    if !s.store.remove_image(&slug, &id).await? {
+        return Err(AppError::NotFound("Image not found".into()));
+    }
+    room::broadcast(&s, &slug, WsMsg::BroadcastRemoveImage { id });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /api/snippets/:slug/files` with a `FileData` from `/api/upload-file`.
+pub async fn attach_file(
+    State(s): State<Arc<AppState>>,
+    Path(slug): Path<String>,
+    Json(file): Json<FileData>,
+) -> Result<impl IntoResponse, AppError> {
+    refuse_burn(&load_live(&s, &slug).await?)?;
+    claim_blobs(&s, &slug, &[&file.url]).await?;
+    let added = s.store.add_file(&slug, file.clone()).await?;
+    if added {
+        room::broadcast(&s, &slug, WsMsg::BroadcastFile { file: file.clone() });
+    }
+    Ok(attached(added, file))
+}
+
+pub async fn detach_file(
+    State(s): State<Arc<AppState>>,
+    Path((slug, id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    load_live(&s, &slug).await?;
+    if !s.store.remove_file(&slug, &id).await? {
+        return Err(AppError::NotFound("File not found".into()));
+    }
+    room::broadcast(&s, &slug, WsMsg::BroadcastRemoveFile { id });
+    Ok(StatusCode::NO_CONTENT)
+}
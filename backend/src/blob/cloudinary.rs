@@ -0,0 +1,70 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::{fetch_remote, Blob, BlobKind, BlobStore};
+use crate::error::AppError;
+
+/// Unsigned uploads to Cloudinary. Files go up as raw resources, images as images.
+pub struct CloudinaryStore {
+    cloud_name:    String,
+    upload_preset: String,
+    http:          reqwest::Client,
+}
+
+impl CloudinaryStore {
+    pub fn from_env() -> Self {
+        Self {
+            cloud_name:    std::env::var("CLOUDINARY_CLOUD_NAME").expect("CLOUDINARY_CLOUD_NAME must be set"),
+            upload_preset: std::env::var("CLOUDINARY_UPLOAD_PRESET").expect("CLOUDINARY_UPLOAD_PRESET must be set"),
+            http:          reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for CloudinaryStore {
+    async fn put(&self, _id: &str, kind: BlobKind, blob: Blob) -> Result<String, AppError> {
+        let resource = match kind {
+            BlobKind::Image => "image",
+            BlobKind::Raw   => "raw",
+        };
+        let form = reqwest::multipart::Form::new()
+            .part("file", reqwest::multipart::Part::bytes(blob.data.to_vec())
+                .file_name(blob.name))
+            .text("upload_preset", self.upload_preset.clone())
+            .text("resource_type", resource);
+
+        let url = format!(
+            "https://api.cloudinary.com/v1_1/{}/{resource}/upload",
+            self.cloud_name
+        );
+        let json: serde_json::Value = self.http
+            .post(url)
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| AppError::Internal(format!("cloudinary upload failed: {e}")))?
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("cloudinary response: {e}")))?;
+
+        json["secure_url"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| AppError::Internal(format!("cloudinary rejected upload: {}", json["error"])))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Bytes, AppError> {
+        fetch_remote(&self.http, url).await
+    }
+
+    async fn read(&self, _id: &str) -> Result<Option<Blob>, AppError> {
+        Ok(None)
+    }
+
+    async fn delete(&self, url: &str) -> Result<(), AppError> {
+        // Destroying needs a signed API call; unsigned presets can only upload.
+        tracing::debug!("cloudinary: leaving {url} in place (unsigned uploads cannot be deleted)");
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use async_trait::async_trait;
+use bson::{doc, Bson};
+use bytes::Bytes;
+use futures_util::{AsyncReadExt, AsyncWriteExt, StreamExt, TryStreamExt};
+use mongodb::{
+    gridfs::{FilesCollectionDocument, GridFsBucket},
+    options::{GridFsBucketOptions, GridFsUploadOptions},
+    Database,
+};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, io::ReaderStream};
+
+use super::{blob_id, blob_url, fetch_remote, io_err, valid_id, Blob, BlobBody, BlobKind, BlobStore, ByteStream};
+use crate::error::AppError;
+
+/// Uploads kept in a MongoDB GridFS bucket, keyed by the blob id and served
+/// through `/api/blobs/:id`. Name and content type ride along as file metadata.
+pub struct GridFsStore {
+    bucket:     GridFsBucket,
+    public_url: String,
+    http:       reqwest::Client,
+}
+
+impl GridFsStore {
+    pub fn new(db: &Database, bucket: &str, public_url: &str) -> Self {
+        let opts = GridFsBucketOptions::builder().bucket_name(bucket.to_string()).build();
+        Self {
+            bucket:     db.gridfs_bucket(opts),
+            public_url: public_url.to_string(),
+            http:       reqwest::Client::new(),
+        }
+    }
+
+    async fn file_doc(&self, id: &str) -> Result<Option<FilesCollectionDocument>, AppError> {
+        let mut cursor = self.bucket.find(doc! { "_id": id }, None).await?;
+        Ok(cursor.try_next().await?)
+    }
+}
+
+#[async_trait]
+impl BlobStore for GridFsStore {
+    async fn put(&self, id: &str, kind: BlobKind, blob: Blob) -> Result<String, AppError> {
+        let data = blob.data;
+        let body = futures_util::stream::once(async move { Ok(data) }).boxed();
+        self.put_stream(id, kind, blob.name, blob.mime, body).await.map(|(url, _)| url)
+    }
+
+    async fn put_stream(
+        &self,
+        id: &str,
+        _kind: BlobKind,
+        name: String,
+        mime: String,
+        mut body: ByteStream<'_>,
+    ) -> Result<(String, u64), AppError> {
+        if !valid_id(id) {
+            return Err(AppError::BadRequest("Invalid blob id".into()));
+        }
+        let opts = GridFsUploadOptions::builder()
+            .metadata(doc! { "mime": &mime, "name": &name })
+            .build();
+        let mut upload = self.bucket.open_upload_stream_with_id(Bson::String(id.to_string()), &name, opts);
+
+        let mut size = 0u64;
+        while let Some(chunk) = body.next().await {
+            let written = match chunk {
+                Ok(chunk) => upload.write_all(&chunk).await.map(|_| chunk.len()),
+                Err(e)    => Err(e),
+            };
+            match written {
+                Ok(n) => size += n as u64,
+                Err(e) => {
+                    let _ = upload.abort().await;
+                    return Err(AppError::BadRequest(format!("upload: {e}")));
+                }
+            }
+        }
+        upload.close().await.map_err(io_err)?;
+        Ok((blob_url(&self.public_url, id), size))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Bytes, AppError> {
+        match blob_id(url) {
+            Some(id) => self
+                .read(id)
+                .await?
+                .map(|b| b.data)
+                .ok_or_else(|| AppError::NotFound("Blob not found".into())),
+            None => fetch_remote(&self.http, url).await,
+        }
+    }
+
+    async fn open(&self, url: &str) -> Result<BlobBody, AppError> {
+        let Some(id) = blob_id(url) else {
+            return Ok(BlobBody::full(fetch_remote(&self.http, url).await?));
+        };
+        let file = self
+            .file_doc(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound("Blob not found".into()))?;
+        let download = self.bucket.open_download_stream(file.id).await?;
+
+        Ok(BlobBody {
+            len:    Some(file.length),
+            stream: ReaderStream::new(download.compat()).boxed(),
+        })
+    }
+
+    async fn read(&self, id: &str) -> Result<Option<Blob>, AppError> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        let Some(file) = self.file_doc(id).await? else { return Ok(None) };
+
+        let meta = file.metadata.unwrap_or_default();
+        let mime = meta.get_str("mime").unwrap_or("application/octet-stream").to_string();
+        let name = meta.get_str("name").ok().map(str::to_string)
+            .or(file.filename)
+            .unwrap_or_else(|| id.to_string());
+
+        let mut data = Vec::with_capacity(file.length as usize);
+        self.bucket
+            .open_download_stream(file.id)
+            .await?
+            .read_to_end(&mut data)
+            .await
+            .map_err(io_err)?;
+
+        Ok(Some(Blob { name, mime, data: data.into() }))
+    }
+
+    async fn delete(&self, url: &str) -> Result<(), AppError> {
+        let Some(id) = blob_id(url) else { return Ok(()) };
+        if self.file_doc(id).await?.is_some() {
+            self.bucket.delete(Bson::String(id.to_string())).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use super::{blob_id, blob_url, fetch_remote, io_err, valid_id, Blob, BlobBody, BlobKind, BlobStore, ByteStream};
+use crate::error::AppError;
+
+/// Keeps uploads on local disk and serves them through `/api/blobs/:id`.
+/// Each blob is `<dir>/<id>` plus a `<dir>/<id>.json` sidecar with its name and type.
+pub struct LocalStore {
+    dir:        PathBuf,
+    public_url: String,
+    http:       reqwest::Client,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    name: String,
+    mime: String,
+}
+
+impl LocalStore {
+    pub fn new(dir: impl Into<PathBuf>, public_url: &str) -> Self {
+        Self { dir: dir.into(), public_url: public_url.to_string(), http: reqwest::Client::new() }
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    async fn write_meta(&self, id: &str, name: String, mime: String) -> Result<(), AppError> {
+        let meta = serde_json::to_vec(&Meta { name, mime }).unwrap();
+        tokio::fs::write(self.meta_path(id), meta).await.map_err(io_err)
+    }
+}
+
+async fn remove_if_present(path: PathBuf) -> Result<(), AppError> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(io_err(e)),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    async fn put(&self, id: &str, _kind: BlobKind, blob: Blob) -> Result<String, AppError> {
+        if !valid_id(id) {
+            return Err(AppError::BadRequest("Invalid blob id".into()));
+        }
+        tokio::fs::create_dir_all(&self.dir).await.map_err(io_err)?;
+
+        tokio::fs::write(self.data_path(id), &blob.data).await.map_err(io_err)?;
+        self.write_meta(id, blob.name, blob.mime).await?;
+        Ok(blob_url(&self.public_url, id))
+    }
+
+    async fn put_stream(
+        &self,
+        id: &str,
+        _kind: BlobKind,
+        name: String,
+        mime: String,
+        mut body: ByteStream<'_>,
+    ) -> Result<(String, u64), AppError> {
+        if !valid_id(id) {
+            return Err(AppError::BadRequest("Invalid blob id".into()));
+        }
+        tokio::fs::create_dir_all(&self.dir).await.map_err(io_err)?;
+
+        let mut file = tokio::fs::File::create(self.data_path(id)).await.map_err(io_err)?;
+        let mut size = 0u64;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(e) => {
+                    drop(file);
+                    remove_if_present(self.data_path(id)).await?;
+                    return Err(AppError::BadRequest(format!("upload: {e}")));
+                }
+            };
+            file.write_all(&chunk).await.map_err(io_err)?;
+            size += chunk.len() as u64;
+        }
+        file.flush().await.map_err(io_err)?;
+
+        self.write_meta(id, name, mime).await?;
+        Ok((blob_url(&self.public_url, id), size))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Bytes, AppError> {
+        match blob_id(url) {
+            Some(id) => self
+                .read(id)
+                .await?
+                .map(|b| b.data)
+                .ok_or_else(|| AppError::NotFound("Blob not found".into())),
+            None => fetch_remote(&self.http, url).await,
+        }
+    }
+
+    async fn open(&self, url: &str) -> Result<BlobBody, AppError> {
+        let Some(id) = blob_id(url) else {
+            return Ok(BlobBody::full(fetch_remote(&self.http, url).await?));
+        };
+        let file = match tokio::fs::File::open(self.data_path(id)).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(AppError::NotFound("Blob not found".into()))
+            }
+            Err(e) => return Err(io_err(e)),
+        };
+        let len = file.metadata().await.ok().map(|m| m.len());
+        Ok(BlobBody { len, stream: ReaderStream::new(file).boxed() })
+    }
+
+    async fn read(&self, id: &str) -> Result<Option<Blob>, AppError> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        let data = match tokio::fs::read(self.data_path(id)).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_err(e)),
+        };
+        let meta = tokio::fs::read(self.meta_path(id))
+            .await
+            .ok()
+            .and_then(|m| serde_json::from_slice::<Meta>(&m).ok())
+            .unwrap_or_else(|| Meta { name: id.to_string(), mime: "application/octet-stream".into() });
+
+        Ok(Some(Blob { name: meta.name, mime: meta.mime, data: data.into() }))
+    }
+
+    async fn delete(&self, url: &str) -> Result<(), AppError> {
+        let Some(id) = blob_id(url) else { return Ok(()) };
+        remove_if_present(self.data_path(id)).await?;
+        remove_if_present(self.meta_path(id)).await
+    }
+}
@@ -0,0 +1,157 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream::BoxStream, StreamExt};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::error::AppError;
+
+pub mod cloudinary;
+pub mod gridfs;
+pub mod local;
+pub mod s3;
+
+pub use cloudinary::CloudinaryStore;
+pub use gridfs::GridFsStore;
+pub use local::LocalStore;
+pub use s3::S3Store;
+
+pub type ByteStream<'a> = BoxStream<'a, Result<Bytes, std::io::Error>>;
+
+/// Blob contents on their way out to a client, streamed when the backend can.
+pub struct BlobBody {
+    pub len:    Option<u64>,
+    pub stream: ByteStream<'static>,
+}
+
+impl BlobBody {
+    /// A body that is already fully in memory.
+    pub fn full(data: Bytes) -> Self {
+        BlobBody {
+            len:    Some(data.len() as u64),
+            stream: futures_util::stream::once(async move { Ok(data) }).boxed(),
+        }
+    }
+}
+
+/// Whether an upload is shown inline as an image or offered as a download.
+/// Some backends (Cloudinary) route the two to different endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobKind {
+    Image,
+    Raw,
+}
+
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub name: String,
+    pub mime: String,
+    pub data: Bytes,
+}
+
+/// Where uploaded images and files live. Snippets only keep the returned URL.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores `blob` under `id` and returns the URL clients should use for it.
+    async fn put(&self, id: &str, kind: BlobKind, blob: Blob) -> Result<String, AppError>;
+
+    /// Like `put`, but for a body that is still arriving. Returns the URL and
+    /// the number of bytes stored. The default buffers the whole body first.
+    async fn put_stream(
+        &self,
+        id: &str,
+        kind: BlobKind,
+        name: String,
+        mime: String,
+        mut body: ByteStream<'_>,
+    ) -> Result<(String, u64), AppError> {
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            buf.extend_from_slice(&chunk.map_err(|e| AppError::BadRequest(format!("upload: {e}")))?);
+        }
+        let size = buf.len() as u64;
+        let url  = self.put(id, kind, Blob { name, mime, data: buf.freeze() }).await?;
+        Ok((url, size))
+    }
+
+    /// Fetches the bytes behind a URL previously returned by `put`.
+    async fn fetch(&self, url: &str) -> Result<Bytes, AppError>;
+
+    /// Streaming counterpart of `fetch`. The default fetches everything up front.
+    async fn open(&self, url: &str) -> Result<BlobBody, AppError> {
+        Ok(BlobBody::full(self.fetch(url).await?))
+    }
+
+    /// Reads a blob served by `/api/blobs/:id`. Backends that hand out their
+    /// own public URLs return `None`.
+    async fn read(&self, id: &str) -> Result<Option<Blob>, AppError>;
+
+    /// Removes the blob behind `url`. URLs this store does not own are ignored.
+    async fn delete(&self, url: &str) -> Result<(), AppError>;
+}
+
+/// Picks the blob store from `BLOB_STORE` (`local`, `s3`, `gridfs` or `cloudinary`).
+/// Without it, Cloudinary is used when its credentials are set, otherwise local disk.
+pub async fn connect(public_url: &str) -> Arc<dyn BlobStore> {
+    let kind = std::env::var("BLOB_STORE").unwrap_or_else(|_| {
+        if std::env::var("CLOUDINARY_CLOUD_NAME").is_ok() { "cloudinary".into() } else { "local".into() }
+    });
+
+    match kind.as_str() {
+        "cloudinary" => {
+            info!("using Cloudinary blob store");
+            Arc::new(CloudinaryStore::from_env())
+        }
+        "local" => {
+            let dir = std::env::var("BLOB_DIR").unwrap_or_else(|_| "./data/blobs".into());
+            info!("using local blob store at {dir}");
+            Arc::new(LocalStore::new(dir, public_url))
+        }
+        "s3" => {
+            info!("using S3 blob store");
+            Arc::new(S3Store::from_env(public_url))
+        }
+        "gridfs" => {
+            // Defaults to the snippet database so a Mongo deployment needs nothing else.
+            let url = std::env::var("GRIDFS_URL")
+                .or_else(|_| std::env::var("DATABASE_URL"))
+                .expect("GRIDFS_URL or DATABASE_URL must be set");
+            let bucket = std::env::var("GRIDFS_BUCKET").unwrap_or_else(|_| "uploads".into());
+            info!("using GridFS blob store (bucket {bucket})");
+            Arc::new(GridFsStore::new(&crate::db::open_database(&url).await, &bucket, public_url))
+        }
+        other => panic!("Unknown BLOB_STORE '{other}'"),
+    }
+}
+
+/// The `/api/blobs/:id` URL for a blob served by this backend.
+pub fn blob_url(public_url: &str, id: &str) -> String {
+    format!("{}/api/blobs/{id}", public_url.trim_end_matches('/'))
+}
+
+/// Inverse of `blob_url`: the id of a blob this backend serves, if `url` is one.
+pub fn blob_id(url: &str) -> Option<&str> {
+    url.rsplit_once("/api/blobs/").map(|(_, id)| id).filter(|id| valid_id(id))
+}
+
+/// Blob ids end up in file paths and object keys, so only accept what `put` hands out.
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub(crate) fn io_err(e: std::io::Error) -> AppError {
+    AppError::Internal(format!("blob io: {e}"))
+}
+
+/// Plain GET for URLs that point somewhere else, e.g. rows written before a
+/// deployment switched blob backends.
+pub async fn fetch_remote(http: &reqwest::Client, url: &str) -> Result<Bytes, AppError> {
+    http.get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| AppError::Internal(format!("fetch failed: {e}")))?
+        .bytes()
+        .await
+        .map_err(|e| AppError::Internal(format!("read body failed: {e}")))
+}
@@ -0,0 +1,56 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{room::Room, AppState, WsMsg};
+
+/// Longest message accepted, in characters; longer ones are cut.
+const MAX_TEXT: usize = 2000;
+
+/// A line of room chat. The author's name and colour are copied in when it is
+/// sent, so history still reads right after they rename or leave.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id:      String,
+    pub slug:    String,
+    pub conn:    u64,
+    pub name:    String,
+    pub color:   String,
+    pub text:    String,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Relays a message from connection `conn` to the room and appends it to the
+/// slug's history. Blank messages are dropped. A message sent while someone
+/// is joining can reach them twice, in the handshake and live; `id` tells the
+/// copies apart.
+pub async fn post(s: &AppState, room: &Room, slug: &str, conn: u64, text: String) {
+    let text: String = text.trim_end().chars().take(MAX_TEXT).collect();
+    if text.trim().is_empty() {
+        return;
+    }
+    let Some(author) = room.participant(conn) else { return };
+    let msg = ChatMessage {
+        id:      uuid::Uuid::new_v4().to_string(),
+        slug:    slug.to_string(),
+        conn,
+        name:    author.name,
+        color:   author.color,
+        text,
+        sent_at: Utc::now(),
+    };
+    room.send(&WsMsg::BroadcastChat { message: msg.clone() });
+    if let Err(e) = s.store.add_chat(&msg, s.config.chat_history).await {
+        warn!("failed to save chat message for /{slug}: {e:?}");
+    }
+}
+
+/// The slug's recent chat, oldest first, for the `Connected` handshake.
+/// Failures are logged and leave the history empty rather than refusing the
+/// socket.
+pub async fn history(s: &AppState, slug: &str) -> Vec<ChatMessage> {
+    s.store.list_chat(slug, s.config.chat_history).await.unwrap_or_else(|e| {
+        warn!("failed to load chat for /{slug}: {e:?}");
+        Vec::new()
+    })
+}
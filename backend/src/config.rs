@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+/// Tunables read once at startup. Every value has a default, so none of the
+/// variables below need to be set.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `REVISION_COALESCE_SECS`: edits landing within this long of the latest
+    /// revision's start fold into it instead of opening a new one.
+    pub revision_coalesce: Duration,
+    /// `WRITE_BEHIND_MS`: how long a live room's edits may sit in memory
+    /// before they are written to the store.
+    pub write_behind:      Duration,
+    /// `PATCH_HISTORY`: how many versions back a position-based patch may be
+    /// based and still get rebased rather than rejected.
+    pub patch_history:     usize,
+    /// `CHAT_HISTORY`: how many chat messages are kept per room and replayed
+    /// to sockets that join.
+    pub chat_history:      usize,
+    /// `ROOM_GRACE_SECS`: how long a room outlives its last socket, so a
+    /// reconnect finds it as it was.
+    pub room_grace:        Duration,
+    /// `ROOM_CHANNEL_CAPACITY`: frames a room buffers for each socket. One
+    /// that falls further behind gets a full resync instead.
+    pub room_capacity:     usize,
+    /// `ADMIN_TOKEN`: bearer token for the room registry at `/api/rooms`,
+    /// which is off while this is unset.
+    pub admin_token:       Option<String>,
+    /// `FANOUT_HEARTBEAT_SECS`: how often instances sharing rooms over
+    /// `FANOUT_URL` send each other their rosters. One silent for three
+    /// heartbeats is taken out of its rooms.
+    pub fanout_heartbeat:  Duration,
+    /// `WS_PING_SECS`: how often sockets are pinged; 0 turns pings off.
+    pub ws_ping:           Duration,
+    /// `WS_PONG_TIMEOUT_SECS`: how long a socket has to answer a ping, or to
+    /// take a write, before it is dropped as dead.
+    pub ws_pong_timeout:   Duration,
+    /// `WS_IDLE_SECS`: a socket nothing has arrived from, pongs included, for
+    /// this long is dropped; 0 turns it off.
+    pub ws_idle:           Duration,
+    /// `REPLAY_BUFFER`: frames a room keeps for sockets that reconnect with
+    /// `?since=` or fall behind. A gap longer than this gets a full snapshot.
+    pub replay_buffer:     usize,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            revision_coalesce: secs("REVISION_COALESCE_SECS", 60),
+            write_behind:      millis("WRITE_BEHIND_MS", 2000),
+            patch_history:     int("PATCH_HISTORY", 256) as usize,
+            chat_history:      int("CHAT_HISTORY", 100) as usize,
+            room_grace:        secs("ROOM_GRACE_SECS", 30),
+            room_capacity:     int("ROOM_CHANNEL_CAPACITY", 128) as usize,
+            admin_token:       std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            fanout_heartbeat:  secs("FANOUT_HEARTBEAT_SECS", 10).max(Duration::from_secs(1)),
+            ws_ping:           secs("WS_PING_SECS", 30),
+            ws_pong_timeout:   secs("WS_PONG_TIMEOUT_SECS", 10).max(Duration::from_secs(1)),
+            ws_idle:           secs("WS_IDLE_SECS", 300),
+            replay_buffer:     int("REPLAY_BUFFER", 512) as usize,
+        }
+    }
+}
+
+fn int(var: &str, default: u64) -> u64 {
+    std::env::var(var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn secs(var: &str, default: u64) -> Duration {
+    Duration::from_secs(int(var, default))
+}
+
+fn millis(var: &str, default: u64) -> Duration {
+    Duration::from_millis(int(var, default))
+}
@@ -0,0 +1,600 @@
+//! Replicated text for live rooms, as an RGA (replicated growable array).
+//!
+//! Every character carries an id that is unique across replicas. An insert
+//! names the character it goes after, and a delete only hides characters.
+//! Replicas that apply the same operations converge on the same text even
+//! when concurrent operations arrive in different orders. The server relays
+//! operations in one order, so a client never sees an operation before the
+//! characters it refers to.
+
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use std::{sync::OnceLock, time::Duration};
+
+use crate::ot::{self, TextOp};
+
+/// Connection reported for edits the server makes itself: full-content
+/// `Edit`s, REST patches and restores. Clients are handed a random non-zero
+/// site on connect.
+pub const SERVER_SITE: u64 = 0;
+
+/// Ids stay below 2^53, the same bound `new_site` keeps to, so browsers can
+/// hold them as plain numbers and the clock can never be pushed to overflow.
+const MAX_SEQ: u64 = 1 << 53;
+
+/// Upper bound on diffing a full-content edit, which runs with the room
+/// locked. Past it the diff is less minimal but still correct.
+const DIFF_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Stands for the start of the document where an anchor must be spelled out.
+/// No character has seq 0.
+const START: Id = Id { seq: 0, site: 0 };
+
+/// Longest changed stretch, old and new together, that a full-content edit
+/// diffs character by character. A longer one is replaced wholesale.
+const DIFF_CHARS: usize = 20_000;
+
+/// Orders by `seq` first, so a later insert sorts above everything it could
+/// have seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Id {
+    pub seq:  u64,
+    pub site: u64,
+}
+
+/// A fresh client site: random, non-zero, and below 2^53 so browsers can hold
+/// it as a plain number.
+pub fn new_site() -> u64 {
+    (uuid::Uuid::new_v4().as_u64_pair().0 >> 11).max(1)
+}
+
+/// Site the ids of this process's own edits carry. Random rather than fixed,
+/// so instances sharing a room never hand out the same id.
+fn local_site() -> u64 {
+    static SITE: OnceLock<u64> = OnceLock::new();
+    *SITE.get_or_init(new_site)
+}
+
+impl Id {
+    fn offset(self, n: u64) -> Option<Id> {
+        Some(Id { seq: self.seq.checked_add(n)?, site: self.site })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    /// Inserts `text` right after `after`, or at the start when it is `None`.
+    /// Its characters take the ids `id`, `id + 1`, … of the same site.
+    Insert { id: Id, after: Option<Id>, text: String },
+    /// Hides the `len` characters with ids `id`, `id + 1`, … of one site.
+    Delete { id: Id, len: u64 },
+}
+
+impl Op {
+    /// Whether a client on `site` may send this. Its inserts must use its own
+    /// site, so it cannot take another's ids, and stay below `MAX_SEQ`.
+    /// Deletes may name anyone's characters.
+    pub fn allowed_from(&self, site: u64) -> bool {
+        match self {
+            Op::Insert { id, text, .. } => id.site == site
+                && id.seq.checked_add(text.chars().count() as u64).is_some_and(|end| end <= MAX_SEQ),
+            Op::Delete { .. } => true,
+        }
+    }
+}
+
+/// A stretch of characters with consecutive ids from one site, in document
+/// order. The whole document, tombstones included, as sent on connect and
+/// persisted alongside the plain text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub id:      Id,
+    /// What the first character was inserted after, when that is not the
+    /// character before it here; seq 0 for the start. Each later character
+    /// was inserted after the one before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after:   Option<Id>,
+    pub text:    String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+/// What applying some operations did: the ones accepted, in CRDT form for
+/// relaying, and their effect on the visible text for position-based clients.
+#[derive(Debug, Default)]
+pub struct Applied {
+    pub ops:   Vec<Op>,
+    pub edits: Vec<TextOp>,
+}
+
+impl Applied {
+    fn push(&mut self, op: Op, edits: Vec<TextOp>) {
+        self.ops.push(op);
+        self.edits.extend(edits);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Elem {
+    id:      Id,
+    /// The anchor it was inserted with, kept so a copy can be merged into
+    /// another replica exactly.
+    after:   Option<Id>,
+    ch:      char,
+    deleted: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Doc {
+    elems: Vec<Elem>,
+    /// Highest `seq` seen; the server's next insert starts above it.
+    clock: u64,
+}
+
+impl Doc {
+    /// Gives every character an id from `SERVER_SITE`, so every instance
+    /// loading the same text ends up with the same document.
+    pub fn from_text(text: &str) -> Self {
+        let mut doc = Doc::default();
+        doc.replace_as(text, SERVER_SITE);
+        doc
+    }
+
+    /// Renumbers the visible text as `from_text` would, dropping deleted
+    /// characters for good. Every id changes, so replicas holding the old ones
+    /// must start over. Returns false, changing nothing, if none were deleted.
+    pub fn compact(&mut self) -> bool {
+        if !self.elems.iter().any(|e| e.deleted) {
+            return false;
+        }
+        *self = Doc::from_text(&self.text());
+        true
+    }
+
+    /// The visible text.
+    pub fn text(&self) -> String {
+        self.elems.iter().filter(|e| !e.deleted).map(|e| e.ch).collect()
+    }
+
+    fn position(&self, id: Id) -> Option<usize> {
+        self.elems.iter().position(|e| e.id == id)
+    }
+
+    fn visible(&self) -> Vec<Id> {
+        self.elems.iter().filter(|e| !e.deleted).map(|e| e.id).collect()
+    }
+
+    fn covers(e: &Elem, id: Id, end: u64) -> bool {
+        e.id.site == id.site && e.id.seq >= id.seq && e.id.seq < end
+    }
+
+    /// Applies one operation and returns its effect on the visible text. Returns
+    /// `None`, changing nothing, if it refers to a character this replica has
+    /// never seen or reuses an existing id.
+    pub fn apply(&mut self, op: &Op) -> Option<Vec<TextOp>> {
+        match op {
+            Op::Insert { id, after, text } => {
+                let len = text.chars().count() as u64;
+                let end = id.seq.checked_add(len).filter(|_| len > 0)?;
+                if self.elems.iter().any(|e| Self::covers(e, *id, end)) {
+                    return None;
+                }
+                let mut at = match after {
+                    None    => 0,
+                    Some(a) => self.position(*a)? + 1,
+                };
+                // Newer siblings of the same anchor, and everything inserted
+                // after them, stay in front.
+                while at < self.elems.len() && self.elems[at].id > *id {
+                    at += 1;
+                }
+                let elems = text.chars().zip(id.seq..end).map(|(ch, seq)| Elem {
+                    id:      Id { seq, site: id.site },
+                    after:   if seq == id.seq { *after } else { Some(Id { seq: seq - 1, site: id.site }) },
+                    ch,
+                    deleted: false,
+                });
+                let pos = self.elems[..at].iter().filter(|e| !e.deleted).count();
+                self.elems.splice(at..at, elems);
+                self.clock = self.clock.max(end - 1);
+                Some(vec![TextOp::Insert { pos, text: text.clone() }])
+            }
+            Op::Delete { id, len } => {
+                let end = id.seq.checked_add(*len)?;
+                let (mut known, mut pos) = (false, 0);
+                let mut edits: Vec<TextOp> = Vec::new();
+                for e in &mut self.elems {
+                    if Self::covers(e, *id, end) {
+                        known = true;
+                        if !std::mem::replace(&mut e.deleted, true) {
+                            match edits.last_mut() {
+                                Some(TextOp::Delete { pos: p, len }) if *p == pos => *len += 1,
+                                _ => edits.push(TextOp::Delete { pos, len: 1 }),
+                            }
+                        }
+                    } else if !e.deleted {
+                        pos += 1;
+                    }
+                }
+                known.then_some(edits)
+            }
+        }
+    }
+
+    /// Applies operations in order, keeping the ones that were accepted.
+    pub fn apply_all(&mut self, ops: Vec<Op>) -> Applied {
+        let mut applied = Applied::default();
+        for op in ops {
+            if let Some(edits) = self.apply(&op) {
+                applied.push(op, edits);
+            }
+        }
+        applied
+    }
+
+    /// Applies position-based edits as server-site operations. `None`, changing
+    /// nothing, if they do not fit the current text.
+    pub fn splice(&mut self, edits: &[TextOp]) -> Option<Applied> {
+        if !ot::fits(edits, self.elems.iter().filter(|e| !e.deleted).count()) {
+            return None;
+        }
+        let mut applied = Applied::default();
+        for edit in edits {
+            let visible = self.visible();
+            let ops = match edit {
+                TextOp::Insert { pos, text } => vec![Op::Insert {
+                    id:    Id { seq: self.clock + 1, site: local_site() },
+                    after: pos.checked_sub(1).map(|i| visible[i]),
+                    text:  text.clone(),
+                }],
+                TextOp::Delete { pos, len } => deletes(&visible[*pos..pos + len]),
+            };
+            for op in ops {
+                let edits = self.apply(&op).unwrap_or_default();
+                applied.push(op, edits);
+            }
+        }
+        Some(applied)
+    }
+
+    /// Turns the visible text into `new` as server-site operations.
+    pub fn replace(&mut self, new: &str) -> Applied {
+        self.replace_as(new, local_site())
+    }
+
+    fn replace_as(&mut self, new: &str, site: u64) -> Applied {
+        let visible = self.visible();
+        let old: Vec<char> = self.text().chars().collect();
+        let new: Vec<char> = new.chars().collect();
+
+        // Most edits touch one spot; only what lies between the common prefix
+        // and suffix needs diffing.
+        let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+        let gone: String  = old[prefix..old.len() - suffix].iter().collect();
+        let added: String = new[prefix..new.len() - suffix].iter().collect();
+        let changes: Vec<(ChangeTag, String)> = if gone.chars().count() + added.chars().count() <= DIFF_CHARS {
+            TextDiff::configure()
+                .timeout(DIFF_TIMEOUT)
+                .diff_chars(gone.as_str(), added.as_str())
+                .iter_all_changes()
+                .map(|c| (c.tag(), c.value().to_string()))
+                .collect()
+        } else {
+            vec![(ChangeTag::Delete, gone), (ChangeTag::Insert, added)]
+        };
+
+        let mut ops: Vec<Op> = Vec::new();
+        let mut next = self.clock + 1;
+        let mut pending: Option<(Option<Id>, String)> = None;
+        let mut flush = |pending: &mut Option<(Option<Id>, String)>, ops: &mut Vec<Op>| {
+            if let Some((after, text)) = pending.take() {
+                let id = Id { seq: next, site };
+                next += text.chars().count() as u64;
+                ops.push(Op::Insert { id, after, text });
+            }
+        };
+
+        // Everything is addressed against the text as it was before this call:
+        // deletes only hide characters, so those ids stay valid throughout.
+        let mut i = prefix;
+        for (tag, value) in changes.iter().filter(|(_, v)| !v.is_empty()) {
+            let n = value.chars().count();
+            match tag {
+                ChangeTag::Equal => {
+                    flush(&mut pending, &mut ops);
+                    i += n;
+                }
+                ChangeTag::Delete => {
+                    flush(&mut pending, &mut ops);
+                    ops.extend(deletes(&visible[i..i + n]));
+                    i += n;
+                }
+                ChangeTag::Insert => {
+                    let after = i.checked_sub(1).map(|j| visible[j]);
+                    pending.get_or_insert_with(|| (after, String::new())).1.push_str(value);
+                }
+            }
+        }
+        flush(&mut pending, &mut ops);
+
+        let mut applied = Applied::default();
+        for op in ops {
+            let edits = self.apply(&op).unwrap_or_default();
+            applied.push(op, edits);
+        }
+        applied
+    }
+
+    pub fn snapshot(&self) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+        let mut prev: Option<Id> = None;
+        for e in &self.elems {
+            match runs.last_mut() {
+                Some(run) if run.deleted == e.deleted
+                    && e.after == prev
+                    && run.id.offset(run.text.chars().count() as u64) == Some(e.id) => run.text.push(e.ch),
+                _ => runs.push(Run {
+                    id:      e.id,
+                    after:   (e.after != prev).then_some(e.after.unwrap_or(START)),
+                    text:    e.ch.to_string(),
+                    deleted: e.deleted,
+                }),
+            }
+            prev = Some(e.id);
+        }
+        runs
+    }
+
+    /// Loads a snapshot. Older ones carry no anchors; each character is then
+    /// taken to follow the one before it.
+    pub fn from_snapshot(runs: Vec<Run>) -> Option<Self> {
+        let mut doc = Doc::default();
+        for run in runs {
+            for (i, ch) in run.text.chars().enumerate() {
+                let id = run.id.offset(i as u64).filter(|id| id.seq < MAX_SEQ)?;
+                let prev = doc.elems.last().map(|e| e.id);
+                let after = match run.after {
+                    Some(a) if i == 0 => (a != START).then_some(a),
+                    _                 => prev,
+                };
+                doc.clock = doc.clock.max(id.seq);
+                doc.elems.push(Elem { id, after, ch, deleted: run.deleted });
+            }
+        }
+        Some(doc)
+    }
+
+    /// Takes in everything another replica's copy has that this one lacks:
+    /// its unseen inserts, with their original anchors, and its deletes.
+    /// Copies that merge each other end up identical.
+    pub fn merge(&mut self, other: &Doc) -> Applied {
+        let mut ops: Vec<Op> = Vec::new();
+        for e in other.elems.iter().filter(|e| self.position(e.id).is_none()) {
+            // Characters typed one after another go back as one insert.
+            if let Some(Op::Insert { id, text, .. }) = ops.last_mut() {
+                let n = text.chars().count() as u64;
+                if id.offset(n) == Some(e.id) && e.after == id.offset(n - 1) {
+                    text.push(e.ch);
+                    continue;
+                }
+            }
+            ops.push(Op::Insert { id: e.id, after: e.after, text: e.ch.to_string() });
+        }
+        let gone: Vec<Id> = other.elems.iter().filter(|e| e.deleted).map(|e| e.id).collect();
+        ops.extend(deletes(&gone));
+
+        let mut applied = Applied::default();
+        for op in ops {
+            // Deletes of characters already gone here change nothing.
+            if let Some(edits) = self.apply(&op).filter(|edits| !edits.is_empty()) {
+                applied.push(op, edits);
+            }
+        }
+        applied
+    }
+
+    /// Persisted form of the snapshot.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.snapshot()).unwrap()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        Self::from_snapshot(serde_json::from_slice(bytes).ok()?)
+    }
+}
+
+/// Delete operations for `ids`, merging runs of consecutive ids from one site.
+fn deletes(ids: &[Id]) -> Vec<Op> {
+    let mut ops: Vec<Op> = Vec::new();
+    for id in ids {
+        match ops.last_mut() {
+            Some(Op::Delete { id: start, len }) if start.site == id.site && start.seq + *len == id.seq => *len += 1,
+            _ => ops.push(Op::Delete { id: *id, len: 1 }),
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Edits made concurrently on copies of `base` by sites 1 and 2.
+    fn concurrent(base: &Doc, a: &str, b: &str) -> (Vec<Op>, Vec<Op>) {
+        (base.clone().replace_as(a, 1).ops, base.clone().replace_as(b, 2).ops)
+    }
+
+    /// Applies both lists to copies of `base` in every interleaving that keeps
+    /// each list in its own order, and checks they all end up identical.
+    fn converge(base: &Doc, a: &[Op], b: &[Op]) -> Doc {
+        fn orders(a: &[Op], b: &[Op]) -> Vec<Vec<Op>> {
+            match (a, b) {
+                ([], rest) | (rest, []) => vec![rest.to_vec()],
+                ([x, xs @ ..], [y, ys @ ..]) => {
+                    let mut all = Vec::new();
+                    for mut o in orders(xs, b) { o.insert(0, x.clone()); all.push(o); }
+                    for mut o in orders(a, ys) { o.insert(0, y.clone()); all.push(o); }
+                    all
+                }
+            }
+        }
+        let docs: Vec<Doc> = orders(a, b)
+            .into_iter()
+            .map(|ops| {
+                let mut doc = base.clone();
+                let n = ops.len();
+                assert_eq!(doc.apply_all(ops).ops.len(), n, "an operation was refused");
+                doc
+            })
+            .collect();
+        for doc in &docs[1..] {
+            assert_eq!(doc.encode(), docs[0].encode());
+        }
+        docs[0].clone()
+    }
+
+    #[test]
+    fn concurrent_inserts_converge() {
+        let base = Doc::from_text("hello world");
+        let (a, b) = concurrent(&base, "hello brave world", "hello world!");
+        assert_eq!(converge(&base, &a, &b).text(), "hello brave world!");
+    }
+
+    #[test]
+    fn concurrent_inserts_at_one_spot_converge() {
+        let base = Doc::from_text("ab");
+        let (a, b) = concurrent(&base, "aXYb", "aZb");
+        let text = converge(&base, &a, &b).text();
+        assert!(text == "aXYZb" || text == "aZXYb", "{text}");
+    }
+
+    #[test]
+    fn concurrent_deletes_converge() {
+        let base = Doc::from_text("hello world");
+        let (a, b) = concurrent(&base, "hello", "o world");
+        assert_eq!(converge(&base, &a, &b).text(), "o");
+    }
+
+    #[test]
+    fn insert_into_concurrently_deleted_text_survives() {
+        let base = Doc::from_text("one two three");
+        let (a, b) = concurrent(&base, "one t-w-o three", "one three");
+        // Which `t` the diff keeps is its own business; the dashes survive.
+        let text = converge(&base, &a, &b).text();
+        assert_eq!(text.matches('-').count(), 2, "{text}");
+        assert!(!text.contains('w'), "{text}");
+    }
+
+    #[test]
+    fn mixed_edits_converge() {
+        let base = Doc::from_text("fn main() {}\n");
+        let (a, b) = concurrent(&base, "fn main() {\n    run();\n}\n", "pub fn start() {}\n// end\n");
+        let text = converge(&base, &a, &b).text();
+        assert!(text.contains("run();") && text.ends_with("// end\n"), "{text}");
+    }
+
+    #[test]
+    fn copies_that_drifted_apart_merge_to_one() {
+        let base = Doc::from_text("shared text");
+        let (mut a, mut b) = (base.clone(), base.clone());
+        // Edits each copy made while the other could not hear them.
+        a.replace_as("shared ... text!", 1);
+        a.replace_as("[shared ... text!", 1);
+        b.replace_as("sharing text", 2);
+        b.replace_as("sharing tex", 2);
+        let (a0, b0) = (a.clone(), b.clone());
+        a.merge(&b0);
+        b.merge(&a0);
+        assert_eq!(a.encode(), b.encode());
+        // Each change lands once, however many times the copies merge.
+        assert!(a.merge(&b).ops.is_empty());
+        assert_eq!(a.text(), b.text());
+    }
+
+    #[test]
+    fn snapshots_keep_anchors() {
+        let mut doc = Doc::from_text("abc");
+        doc.replace_as("Xabc", 4);
+        doc.replace_as("YXabc", 5);
+        doc.replace_as("YXaZbc", 6);
+        let copy = Doc::from_snapshot(doc.snapshot()).unwrap();
+        let mut other = Doc::from_text("abc");
+        other.merge(&copy);
+        assert_eq!(other.encode(), doc.encode());
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        let mut doc = Doc::from_text("hello world");
+        doc.replace_as("help, world", 7);
+        doc.replace_as("hélp, wörld!", 9);
+        let copy = Doc::from_snapshot(doc.snapshot()).unwrap();
+        assert_eq!(copy.text(), doc.text());
+        assert_eq!(copy.encode(), doc.encode());
+        assert_eq!(copy.clock, doc.clock);
+
+        let decoded = Doc::decode(&doc.encode()).unwrap();
+        assert_eq!(decoded.text(), "hélp, wörld!");
+        assert_eq!(decoded.encode(), doc.encode());
+        assert!(Doc::decode(b"not json").is_none());
+    }
+
+    #[test]
+    fn restored_copy_takes_the_same_ops() {
+        let mut doc = Doc::from_text("abc");
+        doc.replace_as("abXc", 3);
+        let mut copy = Doc::decode(&doc.encode()).unwrap();
+        let ops = doc.replace_as("Xc!", 4).ops;
+        copy.apply_all(ops);
+        assert_eq!(copy.encode(), doc.encode());
+    }
+
+    #[test]
+    fn clients_only_insert_under_their_own_site() {
+        let insert = |site, seq| Op::Insert { id: Id { seq, site }, after: None, text: "ab".into() };
+        assert!(insert(5, 1).allowed_from(5));
+        assert!(!insert(6, 1).allowed_from(5));
+        assert!(insert(5, MAX_SEQ - 2).allowed_from(5));
+        assert!(!insert(5, MAX_SEQ - 1).allowed_from(5));
+        assert!(!insert(5, u64::MAX).allowed_from(5));
+        assert!(Op::Delete { id: Id { seq: 1, site: 6 }, len: 1 }.allowed_from(5));
+    }
+
+    #[test]
+    fn snapshots_past_the_id_limit_are_refused() {
+        let run = |seq| Run { id: Id { seq, site: 1 }, after: None, text: "ab".into(), deleted: false };
+        assert!(Doc::from_snapshot(vec![run(MAX_SEQ - 2)]).is_some());
+        assert!(Doc::from_snapshot(vec![run(MAX_SEQ - 1)]).is_none());
+    }
+
+    #[test]
+    fn compact_drops_deleted_characters_only() {
+        let mut doc = Doc::from_text("hello world");
+        assert!(!doc.compact());
+        doc.replace("help world");
+        let text = doc.text();
+        assert!(doc.compact());
+        assert_eq!(doc.text(), text);
+        assert_eq!(doc.encode(), Doc::from_text(&text).encode());
+    }
+
+    #[test]
+    fn long_changes_are_replaced_wholesale() {
+        let old: String = "ab".repeat(DIFF_CHARS);
+        let new: String = format!("<{}>", "ba".repeat(DIFF_CHARS));
+        let mut doc = Doc::from_text(&old);
+        doc.replace(&new);
+        assert_eq!(doc.text(), new);
+    }
+
+    #[test]
+    fn replace_gives_the_new_text() {
+        let mut doc = Doc::default();
+        for new in ["", "hello", "hello world", "world", "wörld 🌍", "", "x\ny\nz", "z\ny\nx"] {
+            doc.replace(new);
+            assert_eq!(doc.text(), new);
+        }
+    }
+}
@@ -1,15 +1,44 @@
 use mongodb::{
     bson::doc,
+    error::ErrorKind,
     options::{ClientOptions, IndexOptions},
     Client, Database, IndexModel,
 };
+use std::{sync::Arc, time::Duration};
 use tracing::info;
 
-pub async fn get_database() -> Database {
-    let mongo_url = std::env::var("DATABASE_URL")
+use crate::store::{MemoryStore, MongoStore, PostgresStore, SnippetStore};
+
+/// Picks the snippet store from the DATABASE_URL scheme:
+/// `postgres://` / `postgresql://` use Postgres, `memory://` keeps everything
+/// in-process, and anything else is handed to MongoDB.
+pub async fn connect() -> Arc<dyn SnippetStore> {
+    let url = std::env::var("DATABASE_URL")
         .expect("DATABASE_URL must be set");
 
-    let mut opts = ClientOptions::parse(&mongo_url)
+    if url.starts_with("memory://") {
+        info!("using in-memory snippet store");
+        return Arc::new(MemoryStore::new());
+    }
+
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        info!("using Postgres snippet store");
+        return Arc::new(PostgresStore::connect(&url).await);
+    }
+
+    Arc::new(MongoStore::new(get_database(&url).await))
+}
+
+pub async fn get_database(mongo_url: &str) -> Database {
+    let db = open_database(mongo_url).await;
+    run_migrations(&db).await;
+    db
+}
+
+/// Connects without touching the snippets collection, for callers that only
+/// need the database handle (e.g. the GridFS blob store).
+pub async fn open_database(mongo_url: &str) -> Database {
+    let mut opts = ClientOptions::parse(mongo_url)
         .await
         .expect("Failed to parse MongoDB URL");
 
@@ -17,12 +46,16 @@ pub async fn get_database() -> Database {
 
     let client = Client::with_options(opts).expect("Failed to create MongoDB client");
     let db_name = std::env::var("MONGODB_DB").unwrap_or_else(|_| "snippets_db".into());
-    let db = client.database(&db_name);
-
-    run_migrations(&db).await;
-    db
+    client.database(&db_name)
 }
 
+/// Server error code for "an index with this name exists with different options".
+const INDEX_OPTIONS_CONFLICT: i32 = 85;
+
+/// How long past `expires_at` the TTL index waits before dropping a row. The
+/// reaper normally gets there first and cleans up uploads too; TTL is the backstop.
+const TTL_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub async fn run_migrations(db: &Database) {
     let col = db.collection::<mongodb::bson::Document>("snippets");
 
@@ -36,11 +69,13 @@ pub async fn run_migrations(db: &Database) {
         )
         .build();
 
+    // TTL index: Mongo drops each document a grace period after `expires_at`.
     let expires_idx = IndexModel::builder()
         .keys(doc! { "expires_at": 1 })
         .options(
             IndexOptions::builder()
                 .name("idx_snippets_expires".to_string())
+                .expire_after(TTL_GRACE)
                 .build(),
         )
         .build();
@@ -49,9 +84,76 @@ pub async fn run_migrations(db: &Database) {
         .await
         .expect("Failed to create slug index");
 
-    col.create_index(expires_idx, None)
+    // Rows written before dates were stored as BSON dates hold RFC3339 strings,
+    // which a TTL index silently ignores.
+    for field in ["created_at", "expires_at"] {
+        let fixed = col
+            .update_many(
+                doc! { field: { "$type": "string" } },
+                vec![doc! { "$set": { field: { "$toDate": format!("${field}") } } }],
+                None,
+            )
+            .await
+            .expect("Failed to convert string dates");
+        if fixed.modified_count > 0 {
+            info!("converted {} {field} values to dates", fixed.modified_count);
+        }
+    }
+
+    if let Err(e) = col.create_index(expires_idx, None).await {
+        // Deployments that predate the TTL (or used another grace) already have
+        // an index under this name; adjust it in place.
+        if !matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == INDEX_OPTIONS_CONFLICT) {
+            panic!("Failed to create expires_at index: {e}");
+        }
+        db.run_command(
+            doc! {
+                "collMod": "snippets",
+                "index": {
+                    "name": "idx_snippets_expires",
+                    "expireAfterSeconds": TTL_GRACE.as_secs() as i64,
+                },
+            },
+            None,
+        )
         .await
-        .expect("Failed to create expires_at index");
+        .expect("Failed to convert expires_at index to TTL");
+    }
+
+    let revisions_idx = IndexModel::builder()
+        .keys(doc! { "slug": 1, "rev": -1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .name("idx_revisions_slug_rev".to_string())
+                .build(),
+        )
+        .build();
+
+    db.collection::<mongodb::bson::Document>("revisions")
+        .create_index(revisions_idx, None)
+        .await
+        .expect("Failed to create revisions index");
+
+    let chat_idx = IndexModel::builder()
+        .keys(doc! { "slug": 1, "seq": -1 })
+        .options(IndexOptions::builder().name("idx_chat_slug_seq".to_string()).build())
+        .build();
+
+    db.collection::<mongodb::bson::Document>("chat")
+        .create_index(chat_idx, None)
+        .await
+        .expect("Failed to create chat index");
+
+    let blobs_idx = IndexModel::builder()
+        .keys(doc! { "slug": 1 })
+        .options(IndexOptions::builder().name("idx_blobs_slug".to_string()).build())
+        .build();
+
+    db.collection::<mongodb::bson::Document>("blobs")
+        .create_index(blobs_idx, None)
+        .await
+        .expect("Failed to create blobs index");
 
     info!("MongoDB indexes ready");
-}
\ No newline at end of file
+}
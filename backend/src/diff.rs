@@ -0,0 +1,161 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use std::sync::Arc;
+
+use crate::{count_view, error::AppError, load_live, refuse_burn, revisions::Revision, AppState};
+
+/// Lines of unchanged context around each hunk unless `?context=` says otherwise.
+const DEFAULT_CONTEXT: usize = 3;
+
+#[derive(Deserialize)]
+pub struct RevisionDiffQuery {
+    from:    u64,
+    /// Defaults to the room's current content.
+    to:      Option<u64>,
+    context: Option<usize>,
+    format:  Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SlugDiffQuery {
+    context: Option<usize>,
+    format:  Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffResponse {
+    pub from:    String,
+    pub to:      String,
+    pub added:   usize,
+    pub removed: usize,
+    pub unified: String,
+    pub hunks:   Vec<Hunk>,
+}
+
+/// One `@@` block of the unified diff. Line numbers are 1-based, as in the
+/// text form.
+#[derive(Debug, Serialize)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_lines: usize,
+    pub new_start: usize,
+    pub new_lines: usize,
+    pub lines:     Vec<HunkLine>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HunkLine {
+    pub tag:      &'static str,
+    pub old_line: Option<usize>,
+    pub new_line: Option<usize>,
+    pub content:  String,
+}
+
+fn diff(from: String, to: String, old: &str, new: &str, context: usize) -> DiffResponse {
+    let diff = TextDiff::from_lines(old, new);
+    let unified = diff
+        .unified_diff()
+        .context_radius(context)
+        .header(&format!("a/{from}"), &format!("b/{to}"))
+        .to_string();
+
+    let (mut added, mut removed) = (0, 0);
+    let hunks = diff
+        .grouped_ops(context)
+        .iter()
+        .filter_map(|group| {
+            let (first, last) = (group.first()?, group.last()?);
+            let old_range = first.old_range().start..last.old_range().end;
+            let new_range = first.new_range().start..last.new_range().end;
+            let lines = group
+                .iter()
+                .flat_map(|op| diff.iter_changes(op))
+                .map(|change| {
+                    let tag = match change.tag() {
+                        ChangeTag::Equal  => "equal",
+                        ChangeTag::Insert => { added   += 1; "insert" }
+                        ChangeTag::Delete => { removed += 1; "delete" }
+                    };
+                    HunkLine {
+                        tag,
+                        old_line: change.old_index().map(|i| i + 1),
+                        new_line: change.new_index().map(|i| i + 1),
+                        content:  change.value().trim_end_matches(['\r', '\n']).to_string(),
+                    }
+                })
+                .collect();
+            Some(Hunk {
+                old_start: old_range.start + 1,
+                old_lines: old_range.len(),
+                new_start: new_range.start + 1,
+                new_lines: new_range.len(),
+                lines,
+            })
+        })
+        .collect();
+
+    DiffResponse { from, to, added, removed, unified, hunks }
+}
+
+async fn revision(s: &AppState, slug: &str, rev: u64) -> Result<Revision, AppError> {
+    s.store
+        .get_revision(slug, rev)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Revision {rev} not found")))
+}
+
+/// JSON by default; `?format=unified` returns just the patch text.
+fn respond(d: DiffResponse, format: Option<&str>) -> Result<Response, AppError> {
+    match format {
+        None | Some("json") => Ok(Json(d).into_response()),
+        Some("unified")     => Ok(([(header::CONTENT_TYPE, "text/x-diff; charset=utf-8")], d.unified).into_response()),
+        Some(other)         => Err(AppError::BadRequest(format!("Unknown diff format '{other}'"))),
+    }
+}
+
+// ── Handlers ──────────────────────────────────────────────────────────────────
+
+/// `GET /api/snippets/:slug/diff?from=1&to=3`: two revisions of one room, or a
+/// revision against the current content when `to` is left out. Counts as a
+/// read, and burn-after-reading rooms are refused, as in `diff_slugs`.
+pub async fn diff_revisions(
+    State(s): State<Arc<AppState>>,
+    Path(slug): Path<String>,
+    Query(q): Query<RevisionDiffQuery>,
+) -> Result<Response, AppError> {
+    refuse_burn(&load_live(&s, &slug).await?)?;
+    let old = revision(&s, &slug, q.from).await?;
+    let newer = match q.to {
+        Some(rev) => Some(revision(&s, &slug, rev).await?),
+        None      => None,
+    };
+    // Only a diff that is actually served counts.
+    let row = count_view(&s, &slug).await?;
+    let (to, new) = match newer {
+        Some(rev) => (format!("{slug}@{}", rev.rev), rev.content),
+        None      => (slug.clone(), row.content),
+    };
+    let d = diff(format!("{slug}@{}", q.from), to, &old.content, &new, q.context.unwrap_or(DEFAULT_CONTEXT));
+    respond(d, q.format.as_deref())
+}
+
+/// `GET /api/diff/:a/:b`: the current content of two rooms. Both count as a
+/// read, and burn-after-reading rooms are refused rather than burned.
+pub async fn diff_slugs(
+    State(s): State<Arc<AppState>>,
+    Path((a, b)): Path<(String, String)>,
+    Query(q): Query<SlugDiffQuery>,
+) -> Result<Response, AppError> {
+    for slug in [&a, &b] {
+        refuse_burn(&load_live(&s, slug).await?)?;
+    }
+    let old = count_view(&s, &a).await?;
+    let new = count_view(&s, &b).await?;
+    let d = diff(a, b, &old.content, &new.content, q.context.unwrap_or(DEFAULT_CONTEXT));
+    respond(d, q.format.as_deref())
+}
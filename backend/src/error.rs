@@ -1,35 +1,52 @@
-// use axum::{
-//     http::StatusCode,
-//     response::{IntoResponse, Json},
-// };
-// use serde_json::json;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json},
+};
+use serde_json::json;
 
-// #[derive(Debug)]
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    Gone(String),
+    BadRequest(String),
+    Forbidden(String),
+    Conflict(String),
+    /// An `If-Match` that no longer matches.
+    PreconditionFailed(String),
+    Db(String),
+    Internal(String),
+}
 
-// pub enum AppError {
-//     NotFound(String),
-//     BadRequest(String),
-//     Conflict(String),
-//     Db(sqlx::Error),
-//     Internal(String),
-// }
+impl From<mongodb::error::Error> for AppError {
+    fn from(e: mongodb::error::Error) -> Self {
+        AppError::Db(e.to_string())
+    }
+}
 
-// impl IntoResponse for AppError {
-//     fn into_response(self) -> axum::response::Response {
-//         let (status, message) = match self {
-//             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
-//             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
-//             AppError::Conflict(msg) => (StatusCode::CONFLICT, msg),
-//             AppError::Db(e) => {
-//                 tracing::error!("Database error: {}", e);
-//                 (StatusCode::INTERNAL_SERVER_ERROR, "Database error".to_string())
-//             }
-//             AppError::Internal(msg) => {
-//                 tracing::error!("Internal error: {}", msg);
-//                 (StatusCode::INTERNAL_SERVER_ERROR, msg)
-//             }
-//         };
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        AppError::Db(e.to_string())
+    }
+}
 
-//         (status, Json(json!({ "error": message }))).into_response()
-//     }
-// }
\ No newline at end of file
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, msg) = match self {
+            AppError::NotFound(m)   => (StatusCode::NOT_FOUND, m),
+            AppError::Gone(m)       => (StatusCode::GONE, m),
+            AppError::BadRequest(m) => (StatusCode::BAD_REQUEST, m),
+            AppError::Forbidden(m)  => (StatusCode::FORBIDDEN, m),
+            AppError::Conflict(m)   => (StatusCode::CONFLICT, m),
+            AppError::PreconditionFailed(m) => (StatusCode::PRECONDITION_FAILED, m),
+            AppError::Internal(m)   => {
+                tracing::error!("internal: {m}");
+                (StatusCode::INTERNAL_SERVER_ERROR, m)
+            }
+            AppError::Db(e) => {
+                tracing::error!("db: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "database error".into())
+            }
+        };
+        (status, Json(json!({ "error": msg }))).into_response()
+    }
+}
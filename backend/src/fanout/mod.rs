@@ -0,0 +1,119 @@
+//! Room traffic shared between backend instances, so sockets connected to
+//! different replicas behind a load balancer still end up in one room.
+//!
+//! Each instance keeps its own copy of a room. Messages for everyone in it are
+//! published as they are sent locally, document changes travel as CRDT
+//! operations that every copy applies, and a room opened while another
+//! instance already has it starts from that instance's document. Events can
+//! be lost while the transport is down; once it is back every open room
+//! swaps copies with the others and merges what it missed.
+//!
+//! Broadcasts and viewer counts are cluster-wide; versions are not. Each
+//! instance counts the changes its own copy has applied, so ETags differ
+//! between replicas, and a PATCH with `If-Match` fails with 412 whenever
+//! fan-out is on instead of comparing against a number that means nothing
+//! on this replica.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::{
+    crdt::{Op, Run},
+    room::{self, Participant, Presence},
+    AppState, WsMsg,
+};
+
+pub mod redis;
+
+pub use self::redis::RedisFanout;
+
+/// One event about one room, tagged with the instance that published it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub from:  u64,
+    pub slug:  String,
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// A message for every socket in the room.
+    Broadcast { msg: WsMsg },
+    /// A change to the document, made by connection `conn`.
+    Ops { conn: u64, ops: Vec<Op>, language: String },
+    /// Sent by an instance opening the room; those that have it answer with
+    /// `State`.
+    Hello,
+    /// The sender's document and who is connected to it there.
+    State {
+        runs:         Vec<Run>,
+        language:     String,
+        participants: Vec<Participant>,
+        presence:     Vec<Presence>,
+    },
+    /// Who is connected to the sender, repeated every heartbeat. An instance
+    /// that stops sending these is taken out of the room.
+    Roster { participants: Vec<Participant>, presence: Vec<Presence> },
+    /// The snippet expired or was deleted; `msg` is the room's last message.
+    Close { msg: WsMsg },
+}
+
+/// What a transport hands this instance.
+#[derive(Debug)]
+pub enum Incoming {
+    /// An event from some instance, possibly this one.
+    Envelope(Box<Envelope>),
+    /// Events to or from the others were lost, e.g. while Redis was
+    /// unreachable or the outgoing queue was full.
+    Lost,
+}
+
+/// Carries events between instances. Publishing never waits: events are
+/// queued and sent in order in the background. The queue is bounded, and
+/// events that cannot be sent are dropped and reported as `Incoming::Lost`.
+pub trait Fanout: Send + Sync {
+    /// This process's id, which its own envelopes carry.
+    fn instance(&self) -> u64;
+
+    fn publish(&self, slug: &str, event: Event);
+}
+
+/// Picks the transport from the FANOUT_URL scheme; only `redis://` and
+/// `rediss://` are understood. Unset means a single instance and no fan-out.
+/// Envelopes from other instances arrive on the returned receiver.
+pub fn connect() -> (Option<Arc<dyn Fanout>>, mpsc::UnboundedReceiver<Incoming>) {
+    let (incoming, rx) = mpsc::unbounded_channel();
+    let Ok(url) = std::env::var("FANOUT_URL") else {
+        return (None, rx);
+    };
+    if !(url.starts_with("redis://") || url.starts_with("rediss://")) {
+        panic!("FANOUT_URL must be a redis:// or rediss:// URL");
+    }
+    let channel = std::env::var("FANOUT_CHANNEL").unwrap_or_else(|_| "codeshare:rooms".into());
+    info!("fanning rooms out over Redis channel {channel}");
+    (Some(Arc::new(RedisFanout::connect(&url, channel, incoming))), rx)
+}
+
+/// Applies envelopes from other instances to the local rooms, reconciles them
+/// after a loss, and sends this instance's roster to the others every
+/// `config.fanout_heartbeat`.
+pub fn spawn(state: Arc<AppState>, mut incoming: mpsc::UnboundedReceiver<Incoming>) {
+    let Some(fanout) = state.fanout.clone() else { return };
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(state.config.fanout_heartbeat);
+        loop {
+            tokio::select! {
+                next = incoming.recv() => match next {
+                    Some(Incoming::Envelope(e)) if e.from != fanout.instance() => room::receive(&state, *e),
+                    Some(Incoming::Envelope(_)) => {}
+                    Some(Incoming::Lost) => room::reconcile(&state),
+                    None => break,
+                },
+                _ = tick.tick() => room::heartbeat(&state),
+            }
+        }
+    });
+}
@@ -0,0 +1,133 @@
+use futures_util::StreamExt;
+use ::redis::{aio::MultiplexedConnection, AsyncCommands, Client};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use super::{Envelope, Event, Fanout, Incoming};
+use crate::crdt;
+
+/// Wait before reconnecting after the connection drops.
+const RETRY: Duration = Duration::from_secs(1);
+
+/// Envelopes waiting to be published. Past it new ones are dropped: by the
+/// time Redis took them they would be stale, and the rooms reconcile instead.
+const QUEUE: usize = 1024;
+
+/// Redis pub/sub on one channel shared by every instance. Envelopes are JSON.
+/// Redis keeps nothing, so an instance that is disconnected for a while misses
+/// what was said meanwhile; reconnecting reports the gap as `Incoming::Lost`,
+/// and the heartbeat puts rosters right again.
+pub struct RedisFanout {
+    instance: u64,
+    out:      mpsc::Sender<Envelope>,
+    /// Set when an envelope did not fit in the queue.
+    overflow: Arc<AtomicBool>,
+}
+
+impl RedisFanout {
+    pub fn connect(url: &str, channel: String, incoming: mpsc::UnboundedSender<Incoming>) -> Self {
+        let client = Client::open(url).expect("Invalid FANOUT_URL");
+        let (out, queued) = mpsc::channel(QUEUE);
+        let overflow = Arc::new(AtomicBool::new(false));
+        tokio::spawn(publisher(client.clone(), channel.clone(), queued, overflow.clone(), incoming.clone()));
+        tokio::spawn(subscriber(client, channel, incoming));
+        Self { instance: crdt::new_site(), out, overflow }
+    }
+}
+
+impl Fanout for RedisFanout {
+    fn instance(&self) -> u64 {
+        self.instance
+    }
+
+    fn publish(&self, slug: &str, event: Event) {
+        let envelope = Envelope { from: self.instance, slug: slug.to_string(), event };
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.out.try_send(envelope) {
+            self.overflow.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Connects, retrying for as long as it takes.
+async fn connection(client: &Client) -> MultiplexedConnection {
+    loop {
+        match client.get_multiplexed_async_connection().await {
+            Ok(c)  => return c,
+            Err(e) => {
+                warn!("fanout: connecting to Redis failed: {e}");
+                tokio::time::sleep(RETRY).await;
+            }
+        }
+    }
+}
+
+/// Sends queued envelopes in order. When publishing fails it reconnects, then
+/// drops everything queued during the outage rather than sending it late, and
+/// reports the loss.
+async fn publisher(
+    client: Client,
+    channel: String,
+    mut queued: mpsc::Receiver<Envelope>,
+    overflow: Arc<AtomicBool>,
+    incoming: mpsc::UnboundedSender<Incoming>,
+) {
+    let mut conn = connection(&client).await;
+    while let Some(envelope) = queued.recv().await {
+        let payload = serde_json::to_vec(&envelope).unwrap();
+        let mut lost = overflow.swap(false, Ordering::Relaxed);
+        if let Err(e) = conn.publish::<_, _, ()>(&channel, &payload).await {
+            warn!("fanout: publishing to /{} failed: {e}", envelope.slug);
+            conn = connection(&client).await;
+            let mut stale = 0;
+            while queued.try_recv().is_ok() {
+                stale += 1;
+            }
+            warn!("fanout: dropped {stale} events queued while Redis was unreachable");
+            lost = true;
+        }
+        if lost && incoming.send(Incoming::Lost).is_err() {
+            return;
+        }
+    }
+}
+
+/// Hands every envelope on the channel to `incoming`, reconnecting for as long
+/// as the process runs. Each resubscription reports what was missed meanwhile
+/// as `Incoming::Lost`.
+async fn subscriber(client: Client, channel: String, incoming: mpsc::UnboundedSender<Incoming>) {
+    let mut subscribed_before = false;
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(&channel).await {
+                Ok(()) => {
+                    info!("fanout: subscribed to {channel}");
+                    if std::mem::replace(&mut subscribed_before, true) && incoming.send(Incoming::Lost).is_err() {
+                        return;
+                    }
+                    let mut messages = pubsub.on_message();
+                    while let Some(msg) = messages.next().await {
+                        match serde_json::from_slice::<Envelope>(msg.get_payload_bytes()) {
+                            Ok(envelope) => {
+                                if incoming.send(Incoming::Envelope(Box::new(envelope))).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => warn!("fanout: ignoring malformed envelope: {e}"),
+                        }
+                    }
+                    warn!("fanout: lost the subscription to {channel}");
+                }
+                Err(e) => warn!("fanout: subscribing to {channel} failed: {e}"),
+            },
+            Err(e) => warn!("fanout: connecting to Redis failed: {e}"),
+        }
+        tokio::time::sleep(RETRY).await;
+    }
+}
@@ -0,0 +1,90 @@
+//! Tells live sockets from dead ones. A peer that vanishes without closing,
+//! e.g. behind a proxy or after losing its network, leaves a socket that never
+//! errors on its own; pings and an idle limit find those so their rooms stop
+//! counting them.
+
+use std::time::Duration;
+use tokio::{sync::watch, time::Instant};
+
+use crate::config::Config;
+
+/// What a socket should do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    /// Send a ping.
+    Ping,
+    /// Close it; the peer is gone or has gone quiet.
+    Dead(&'static str),
+    /// Nothing; a deadline passed that the peer had already met.
+    Alive,
+}
+
+/// One socket's timers. `heard` is when any frame last arrived from the peer,
+/// pongs included.
+pub struct Keepalive {
+    ping:         Option<Duration>,
+    pong_timeout: Duration,
+    idle:         Option<Duration>,
+    heard:        watch::Receiver<Instant>,
+    next_ping:    Option<Instant>,
+    /// When the oldest unanswered ping went out.
+    awaiting:     Option<Instant>,
+}
+
+impl Keepalive {
+    pub fn new(config: &Config, heard: watch::Receiver<Instant>) -> Self {
+        let ping = Some(config.ws_ping).filter(|d| !d.is_zero());
+        Self {
+            ping,
+            pong_timeout: config.ws_pong_timeout,
+            idle:         Some(config.ws_idle).filter(|d| !d.is_zero()),
+            heard,
+            next_ping:    ping.map(|p| Instant::now() + p),
+            awaiting:     None,
+        }
+    }
+
+    /// Longest a write to the socket may take before the peer counts as gone.
+    pub fn write_timeout(&self) -> Duration {
+        self.pong_timeout
+    }
+
+    /// Waits for the next deadline and says what to do about it. Safe to drop
+    /// unfinished, as in a `select!`: nothing changes until it resolves.
+    pub async fn tick(&mut self) -> Beat {
+        let heard = *self.heard.borrow();
+        let deadline = [
+            self.next_ping,
+            self.awaiting.map(|t| t + self.pong_timeout),
+            self.idle.map(|i| heard + i),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        match deadline {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None     => std::future::pending().await,
+        }
+
+        let now   = Instant::now();
+        let heard = *self.heard.borrow();
+        if let Some(sent) = self.awaiting {
+            if heard >= sent {
+                self.awaiting = None;
+            } else if now >= sent + self.pong_timeout {
+                return Beat::Dead("no pong");
+            }
+        }
+        if self.idle.is_some_and(|i| now >= heard + i) {
+            return Beat::Dead("idle");
+        }
+        match (self.ping, self.next_ping) {
+            (Some(every), Some(at)) if now >= at => {
+                self.next_ping = Some(now + every);
+                self.awaiting.get_or_insert(now);
+                Beat::Ping
+            }
+            _ => Beat::Alive,
+        }
+    }
+}
@@ -1,49 +1,58 @@
 use axum::{
     body::Body,
-    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Multipart, Path, State},
-    http::{header, HeaderName, Method, StatusCode},
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
     response::{IntoResponse, Json, Response},
-    routing::{delete, get, patch, post},
+    routing::{delete, get, post},
     Router,
 };
-use bson::{doc, to_bson};
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use futures_util::{SinkExt, StreamExt};
-use mongodb::{Collection, Database};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt, TryStreamExt};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::broadcast;
 use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
-use tracing::info;
-use urlencoding;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
 use zip::write::FileOptions;
 
+mod attachments;
+mod blob;
+mod chat;
+mod config;
+mod crdt;
 mod db;
-
-type Rooms = Arc<DashMap<String, broadcast::Sender<String>>>;
-
-fn get_or_create_room(rooms: &Rooms, slug: &str) -> broadcast::Sender<String> {
-    if let Some(tx) = rooms.get(slug) {
-        return tx.clone();
-    }
-    let (tx, _) = broadcast::channel(128);
-    rooms.insert(slug.to_string(), tx.clone());
-    tx
-}
+mod diff;
+mod error;
+mod fanout;
+mod keepalive;
+mod ot;
+mod reaper;
+mod revisions;
+mod room;
+mod store;
+
+use blob::{Blob, BlobKind, BlobStore};
+use chat::ChatMessage;
+use config::Config;
+use crdt::{Op, Run};
+use ot::TextOp;
+use error::AppError;
+use fanout::Fanout;
+use keepalive::{Beat, Keepalive};
+use room::{close_room, get_or_create_room, Feed, Participant, Presence, Range, Rooms};
+use store::{SnippetPatch, SnippetStore};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db:    Database,
-    pub rooms: Rooms,
-}
-
-impl AppState {
-    fn col(&self) -> Collection<SnippetRow> {
-        self.db.collection::<SnippetRow>("snippets")
-    }
+    pub store:  Arc<dyn SnippetStore>,
+    pub blobs:  Arc<dyn BlobStore>,
+    pub rooms:  Rooms,
+    pub config: Arc<Config>,
+    /// Other instances to share rooms with; `None` when running alone.
+    pub fanout: Option<Arc<dyn Fanout>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -53,8 +62,38 @@ pub struct SnippetRow {
     pub language:   String,
     pub images:     Vec<ImageData>,
     pub files:      Vec<FileData>,
+    // Stored as BSON dates (not strings) so Mongo's TTL index can see them.
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub expires_at: DateTime<Utc>,
+    /// Deleted by the first successful read.
+    #[serde(default)]
+    pub burn_after_reading: bool,
+    /// Reads so far: snippet fetches, single-file downloads and zip downloads.
+    #[serde(default)]
+    pub views:      u32,
+    /// Once `views` reaches this the room expires. `None` means unlimited.
+    #[serde(default)]
+    pub max_views:  Option<u32>,
+    /// Encoded `crdt::Doc` from the last flush of a live room. Ignored when it
+    /// no longer matches `content`, e.g. after a REST patch with nobody connected.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub crdt:       Option<Vec<u8>>,
+    /// Bumped by every change to the text or language, from a live room or a
+    /// REST patch alike. Served as the ETag that `If-Match` is checked against.
+    #[serde(default)]
+    pub version:    u64,
+}
+
+impl SnippetRow {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+
+    pub fn remaining_views(&self) -> Option<u32> {
+        self.max_views.map(|max| max.saturating_sub(self.views))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -63,6 +102,11 @@ pub struct CreateRequest {
     pub content:  String,
     pub language: Option<String>,
     pub images:   Option<Vec<ImageData>>,
+    /// "10m", "1h", "1d", "1w", "never" or an RFC3339 timestamp. Defaults to never.
+    pub expires:  Option<String>,
+    #[serde(default)]
+    pub burn_after_reading: bool,
+    pub max_views: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -77,7 +121,7 @@ pub struct ImageData {
 pub struct FileData {
     pub id:   String,   // uuid
     pub name: String,   // original filename
-    pub url:  String,   // Cloudinary secure_url (raw resource)
+    pub url:  String,   // public URL handed out by the blob store
     pub size: u64,      // bytes
     pub mime: String,   // e.g. "application/zip"
 }
@@ -91,6 +135,11 @@ pub struct SnippetResponse {
     pub files:      Vec<FileData>,
     pub created_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
+    pub burn_after_reading: bool,
+    /// `None` when the snippet has no view limit.
+    pub remaining_views: Option<u32>,
+    /// Also sent as the `ETag` header.
+    pub version:    u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -113,37 +162,79 @@ pub enum WsMsg {
     RemoveImage          { id: String },
     File                 { file: FileData },
     RemoveFile           { id: String },
-    Connected            { slug: String, viewers: usize },
+    /// CRDT operations from a client that was handed `site` on connect.
+    Ops                  { ops: Vec<Op> },
+    /// Position-based edits made against room version `base`.
+    Patch                { base: u64, ops: Vec<TextOp> },
+    /// Where this socket's carets are.
+    Cursor               { ranges: Vec<Range> },
+    /// Changes this socket's name and/or colour; `None` keeps the current one.
+    Rename               { name: Option<String>, color: Option<String> },
+    /// A line of chat from this socket.
+    Chat                 { text: String },
+    /// `conn` identifies this socket in presence and patch echoes, and is the
+    /// CRDT site its inserts use. `state` is sent to `?feed=ops` sockets,
+    /// `content` to `?feed=patch` ones; both are as of `version`.
+    ///
+    /// Every broadcast after this carries a `seq` field. A client that
+    /// reconnects with `?epoch=&since=` set to the `epoch` here and the last
+    /// `seq` it saw is `resumed`: the frames it missed follow, and `state` and
+    /// `content` are left out. If that is not possible, a `Resync` follows.
+    Connected            {
+        slug:     String,
+        viewers:  usize,
+        conn:     u64,
+        version:  u64,
+        epoch:    u64,
+        /// The last broadcast this handshake takes in.
+        seq:      u64,
+        resumed:  bool,
+        /// Everyone in the room, this socket included.
+        participants: Vec<Participant>,
+        presence: Vec<Presence>,
+        /// Recent chat, oldest first.
+        chat:     Vec<ChatMessage>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state:   Option<Vec<Run>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
     Viewers              { count: usize },
     BroadcastEdit        { content: String, language: String },
+    BroadcastOps         { ops: Vec<Op> },
+    /// The change that produced `version`, from connection `conn`.
+    BroadcastPatch       { version: u64, conn: u64, ops: Vec<TextOp>, language: String },
+    BroadcastCursor      { conn: u64, ranges: Vec<Range> },
+    Joined               { participant: Participant },
+    Renamed              { participant: Participant },
+    BroadcastChat        { message: ChatMessage },
+    /// Connection `conn` has gone; drop it from the roster along with its carets.
+    Left                 { conn: u64 },
+    /// Sent instead of the frames a socket missed by falling too far behind
+    /// or reconnecting too late: everything it needs to start over, as of
+    /// `version` and broadcast `seq`. `state` is only sent to `?feed=ops`
+    /// sockets.
+    Resync               {
+        version:      u64,
+        seq:          u64,
+        content:      String,
+        language:     String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state:        Option<Vec<Run>>,
+        participants: Vec<Participant>,
+        presence:     Vec<Presence>,
+        images:       Vec<ImageData>,
+        files:        Vec<FileData>,
+    },
+    /// Sent only to the patch's author; it should start over from `content`.
+    PatchRejected        { base: u64, version: u64, content: String },
     BroadcastImage       { image: ImageData },
     BroadcastRemoveImage { id: String },
     BroadcastFile        { file: FileData },
     BroadcastRemoveFile  { id: String },
-}
-
-enum AppError {
-    NotFound(String),
-    BadRequest(String),
-    Conflict(String),
-    Db(mongodb::error::Error),
-    Internal(String),
-}
-
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, msg) = match self {
-            AppError::NotFound(m)   => (StatusCode::NOT_FOUND, m),
-            AppError::BadRequest(m) => (StatusCode::BAD_REQUEST, m),
-            AppError::Conflict(m)   => (StatusCode::CONFLICT, m),
-            AppError::Internal(m)   => (StatusCode::INTERNAL_SERVER_ERROR, m),
-            AppError::Db(e) => {
-                tracing::error!("db: {e}");
-                (StatusCode::INTERNAL_SERVER_ERROR, "database error".into())
-            }
-        };
-        (status, Json(serde_json::json!({ "error": msg }))).into_response()
-    }
+    /// Last message a room ever sends, once it has expired or been deleted;
+    /// the server closes the socket after it.
+    Expired,
 }
 
 fn sanitize(raw: &str) -> String {
@@ -183,12 +274,102 @@ fn never() -> DateTime<Utc> {
         .with_timezone(&Utc)
 }
 
-async fn slug_exists(col: &Collection<SnippetRow>, slug: &str) -> Result<bool, AppError> {
-    Ok(col
-        .count_documents(doc! { "slug": slug }, None)
-        .await
-        .map_err(AppError::Db)?
-        > 0)
+/// Turns a client expiry ("30m", "1h", "1d", "2w", "never" or RFC3339) into
+/// an absolute time. Relative values count from now.
+fn parse_expiry(raw: &str) -> Result<DateTime<Utc>, AppError> {
+    let raw = raw.trim();
+    if raw.eq_ignore_ascii_case("never") {
+        return Ok(never());
+    }
+    if let Ok(at) = DateTime::parse_from_rfc3339(raw) {
+        let at = at.with_timezone(&Utc);
+        if at <= Utc::now() {
+            return Err(AppError::BadRequest("Expiry must be in the future".into()));
+        }
+        return Ok(at.min(never()));
+    }
+
+    let bad = || AppError::BadRequest(format!("Invalid expiry '{raw}' (try 10m, 1h, 1d, 1w or never)"));
+    let split = raw.len().checked_sub(1).filter(|&i| raw.is_char_boundary(i)).ok_or_else(bad)?;
+    let (num, unit) = raw.split_at(split);
+    let n: i64 = num.parse().ok().filter(|n| *n > 0).ok_or_else(bad)?;
+    let dur = match unit {
+        "m" => chrono::Duration::try_minutes(n),
+        "h" => chrono::Duration::try_hours(n),
+        "d" => chrono::Duration::try_days(n),
+        "w" => chrono::Duration::try_weeks(n),
+        _   => None,
+    }.ok_or_else(bad)?;
+    Ok(Utc::now().checked_add_signed(dur).unwrap_or_else(never).min(never()))
+}
+
+/// Loads a room that is still usable: 404 if it never existed, 410 once expired.
+async fn load_live(s: &AppState, slug: &str) -> Result<SnippetRow, AppError> {
+    let mut row = s.store
+        .get(slug)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".into()))?;
+    if row.is_expired() {
+        return Err(AppError::Gone("Room has expired".into()));
+    }
+    room::overlay(&s.rooms, &mut row);
+    Ok(row)
+}
+
+/// Counts one view of a room and returns it as it is afterwards. The view that
+/// hits `max_views` still succeeds; after it the room reads as expired.
+async fn count_view(s: &AppState, slug: &str) -> Result<SnippetRow, AppError> {
+    let mut row = s.store
+        .record_view(slug, Utc::now())
+        .await?
+        .ok_or_else(|| AppError::Gone("Room is no longer available".into()))?;
+    room::overlay(&s.rooms, &mut row);
+    Ok(row)
+}
+
+/// Loads a room for a read of its text other than the snippet itself, such as
+/// a revision. Burn-after-reading rooms are refused rather than burned, and
+/// the read counts as a view.
+async fn load_for_read(s: &AppState, slug: &str) -> Result<SnippetRow, AppError> {
+    refuse_burn(&load_live(s, slug).await?)?;
+    count_view(s, slug).await
+}
+
+fn refuse_burn(row: &SnippetRow) -> Result<(), AppError> {
+    if row.burn_after_reading {
+        return Err(AppError::Forbidden(format!("'{}' can only be read once", row.slug)));
+    }
+    Ok(())
+}
+
+/// Makes `slug` the owner of each upload before it is attached. A blob another
+/// snippet owns is refused; URLs this backend never issued go through unowned,
+/// so deleting the row leaves them alone.
+async fn claim_blobs(s: &AppState, slug: &str, urls: &[&str]) -> Result<(), AppError> {
+    for url in urls {
+        if !s.store.claim_blob(url, slug).await? {
+            return Err(AppError::BadRequest(format!("{url} belongs to another snippet")));
+        }
+    }
+    Ok(())
+}
+
+/// Best-effort removal of everything a deleted row uploaded. Only blobs the
+/// row claimed are touched, whatever URLs it holds. Failures are logged
+/// rather than surfaced; the row itself is already gone.
+async fn delete_blobs(s: &AppState, row: &SnippetRow) {
+    let urls = match s.store.release_blobs(&row.slug).await {
+        Ok(urls) => urls,
+        Err(e)   => {
+            tracing::warn!("failed to release blobs for /{}: {e:?}", row.slug);
+            return;
+        }
+    };
+    for url in &urls {
+        if let Err(e) = s.blobs.delete(url).await {
+            tracing::warn!("failed to delete blob {url} for /{}: {e:?}", row.slug);
+        }
+    }
 }
 
 // ── Handlers ──────────────────────────────────────────────────────────────────
@@ -204,7 +385,7 @@ async fn check_slug(
     let slug  = sanitize(&raw);
     let valid = validate(&slug).is_ok();
     let taken = if valid {
-        slug_exists(&s.col(), &slug).await.unwrap_or(true)
+        s.store.get(&slug).await.map(|r| r.is_some_and(|r| !r.is_expired())).unwrap_or(true)
     } else { true };
     Json(SlugCheck { available: valid && !taken, slug })
 }
@@ -213,26 +394,50 @@ async fn create_snippet(
     State(s): State<Arc<AppState>>,
     Json(req): Json<CreateRequest>,
 ) -> Result<impl IntoResponse, AppError> {
-    let col = s.col();
+    let expires_at = req.expires.as_deref().map(parse_expiry).transpose()?.unwrap_or_else(never);
+    // The uploads would be deleted along with the row on first read, leaving the
+    // reader with dead links, so one-shot snippets are text only.
+    if req.burn_after_reading && req.images.as_ref().is_some_and(|i| !i.is_empty()) {
+        return Err(AppError::BadRequest("Burn-after-reading snippets cannot carry images".into()));
+    }
+    if req.max_views == Some(0) {
+        return Err(AppError::BadRequest("max_views must be at least 1".into()));
+    }
+    // Postgres keeps the count in an INTEGER column.
+    if req.max_views.is_some_and(|m| m > i32::MAX as u32) {
+        return Err(AppError::BadRequest(format!("max_views must be at most {}", i32::MAX)));
+    }
+
     let slug = match req.slug.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
         Some(raw) => {
             let sl = sanitize(raw);
             validate(&sl)?;
-            if slug_exists(&col, &sl).await? {
-                return Ok((StatusCode::OK, Json(CreateResponse { slug: sl, expires_at: never() })));
+            if let Some(existing) = s.store.get(&sl).await? {
+                if !existing.is_expired() {
+                    return Ok((StatusCode::OK, Json(CreateResponse { slug: sl, expires_at: existing.expires_at })));
+                }
+                // An expired room frees its slug for whoever asks next.
+                if let Some(old) = s.store.delete(&sl).await? {
+                    close_room(&s, &sl, &WsMsg::Expired);
+                    delete_blobs(&s, &old).await;
+                }
             }
             sl
         }
         None => {
             let mut sl = gen_slug();
             for _ in 0..10 {
-                if !slug_exists(&col, &sl).await? { break; }
+                if !s.store.exists(&sl).await? { break; }
                 sl = gen_slug();
             }
             sl
         }
     };
 
+    if let Some(images) = &req.images {
+        claim_blobs(&s, &slug, &images.iter().map(|i| i.url.as_str()).collect::<Vec<_>>()).await?;
+    }
+
     let row = SnippetRow {
         slug:       slug.clone(),
         content:    req.content,
@@ -240,29 +445,85 @@ async fn create_snippet(
         images:     req.images.unwrap_or_default(),
         files:      vec![],
         created_at: Utc::now(),
-        expires_at: never(),
+        expires_at,
+        burn_after_reading: req.burn_after_reading,
+        views:      0,
+        max_views:  req.max_views,
+        crdt:       None,
+        version:    0,
     };
 
-    col.insert_one(row, None).await.map_err(AppError::Db)?;
+    let (content, language) = (row.content.clone(), row.language.clone());
+    s.store.create(row).await?;
+    if req.burn_after_reading {
+        // Sockets may already sit in a room under this slug, and a secret must
+        // not reach anyone before its one read. The store keeps it out of the
+        // history.
+        close_room(&s, &slug, &WsMsg::Expired);
+    } else {
+        room::reset(&s, &slug, &content, &language);
+        revisions::record(&s, &slug, &content, &language).await;
+    }
     info!("created /{slug}");
-    Ok((StatusCode::CREATED, Json(CreateResponse { slug, expires_at: never() })))
+    Ok((StatusCode::CREATED, Json(CreateResponse { slug, expires_at })))
 }
 
 async fn get_snippet(
     State(s): State<Arc<AppState>>,
     Path(slug): Path<String>,
 ) -> Result<impl IntoResponse, AppError> {
-    let row = s.col()
-        .find_one(doc! { "slug": &slug }, None)
-        .await
-        .map_err(AppError::Db)?
-        .ok_or_else(|| AppError::NotFound("Room not found".into()))?;
+    let mut row = load_live(&s, &slug).await?;
+
+    if !row.burn_after_reading {
+        row = count_view(&s, &slug).await?;
+    } else {
+        // Find-and-delete: of two racing readers only one gets the row back.
+        row = s.store
+            .delete(&slug)
+            .await?
+            .ok_or_else(|| AppError::NotFound("Room not found".into()))?;
+        delete_blobs(&s, &row).await;
+        info!("burned /{slug}");
+    }
 
-    Ok(Json(SnippetResponse {
+    let remaining_views = row.remaining_views();
+    Ok((etag(&s, row.version), Json(SnippetResponse {
         slug: row.slug, content: row.content, language: row.language,
         images: row.images, files: row.files,
         created_at: row.created_at, expires_at: row.expires_at,
-    }))
+        burn_after_reading: row.burn_after_reading,
+        remaining_views,
+        version: row.version,
+    })))
+}
+
+/// The version as an ETag header, left off with fan-out on since `If-Match`
+/// cannot be honoured then.
+fn etag(s: &AppState, version: u64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if s.fanout.is_none() {
+        headers.insert(header::ETAG, format!("\"{version}\"").parse().unwrap());
+    }
+    headers
+}
+
+/// The version an `If-Match` header asks for. `None` without one or for `*`;
+/// anything that is not one of our ETags can never match.
+fn if_match(headers: &HeaderMap) -> Result<Option<u64>, AppError> {
+    let Some(raw) = headers.get(header::IF_MATCH) else { return Ok(None) };
+    let raw = raw.to_str().unwrap_or_default().trim();
+    if raw == "*" {
+        return Ok(None);
+    }
+    raw.trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .map(Some)
+        .map_err(|_| AppError::PreconditionFailed(format!("If-Match {raw} is not a snippet version")))
+}
+
+fn stale(version: u64) -> AppError {
+    AppError::PreconditionFailed(format!("Snippet has changed; it is now at version {version}"))
 }
 
 #[derive(Deserialize)]
@@ -270,79 +531,128 @@ struct PatchReq {
     content:  Option<String>,
     language: Option<String>,
     images:   Option<Vec<ImageData>>,
+    /// Same forms as `CreateRequest::expires`, counted from now.
+    expires:  Option<String>,
 }
 
+/// With `If-Match`, nothing is written unless the snippet is still at that
+/// version; 412 otherwise, and always 412 with fan-out on. Answers with the
+/// new version as an ETag.
 async fn patch_snippet(
     State(s): State<Arc<AppState>>,
     Path(slug): Path<String>,
+    headers: HeaderMap,
     Json(req): Json<PatchReq>,
 ) -> Result<impl IntoResponse, AppError> {
-    let col    = s.col();
-    let filter = doc! { "slug": &slug };
-    if let Some(c) = req.content {
-        col.update_one(filter.clone(), doc! { "$set": { "content": c } }, None)
-            .await.map_err(AppError::Db)?;
+    let expected = if_match(&headers)?;
+    // Each instance numbers its own copy of a shared room, so a version from
+    // one says nothing about the text on another. Refuse rather than guess.
+    if expected.is_some() && s.fanout.is_some() {
+        return Err(AppError::PreconditionFailed(
+            "If-Match cannot be checked while rooms are shared between instances".into(),
+        ));
+    }
+    let hold = if req.content.is_some() || req.language.is_some() {
+        room::hold(&s, &slug).await?
+    } else {
+        None
+    };
+    let version = write_patch(&s, &slug, expected, req).await;
+    room::unhold(&s, &slug, hold).await;
+    Ok((StatusCode::NO_CONTENT, etag(&s, version?)))
+}
+
+/// The body of `patch_snippet`. Returns the version the snippet is at after.
+async fn write_patch(s: &Arc<AppState>, slug: &str, expected: Option<u64>, req: PatchReq) -> Result<u64, AppError> {
+    let expires_at = req.expires.as_deref().map(parse_expiry).transpose()?;
+    let row = load_live(s, slug).await?;
+    if expected.is_some_and(|v| v != row.version) {
+        return Err(stale(row.version));
     }
-    if let Some(l) = req.language {
-        col.update_one(filter.clone(), doc! { "$set": { "language": l } }, None)
-            .await.map_err(AppError::Db)?;
+    if let Some(images) = &req.images {
+        // One-shot snippets are text only, as in `create_snippet`.
+        if row.burn_after_reading && !images.is_empty() {
+            return Err(AppError::BadRequest("Burn-after-reading snippets cannot carry images".into()));
+        }
+        claim_blobs(s, slug, &images.iter().map(|i| i.url.as_str()).collect::<Vec<_>>()).await?;
     }
-    if let Some(i) = req.images {
-        let b = to_bson(&i).unwrap();
-        col.update_one(filter, doc! { "$set": { "images": b } }, None)
-            .await.map_err(AppError::Db)?;
+    let mut patch = SnippetPatch { images: req.images, expires_at, ..Default::default() };
+
+    // Text goes through the live room when one is open, so connected editors
+    // see it and the write-behind flush does not put the old text back. The
+    // room checks the version itself, under the same lock as the edit.
+    let text = (req.content.is_some() || req.language.is_some()).then(|| (
+        req.content.unwrap_or(row.content),
+        req.language.unwrap_or(row.language),
+    ));
+    let mut live = None;
+    let mut revision = None;
+    if let Some((content, language)) = text {
+        live = room::edit_if(s, slug, crdt::SERVER_SITE, content.clone(), language.clone(), expected)
+            .map_err(stale)?;
+        if live.is_none() {
+            patch.content  = Some(content.clone());
+            patch.language = Some(language.clone());
+            revision = Some((content, language));
+        }
     }
-    Ok(StatusCode::NO_CONTENT)
+    // The stored version lags a live room's, so only check it when the text
+    // went to the store.
+    if live.is_none() && !s.rooms.contains_key(slug) {
+        patch.if_version = expected;
+    }
+
+    let stored = s.store.patch(slug, patch).await?.ok_or_else(|| match expected {
+        Some(_) => AppError::PreconditionFailed("Snippet has changed".into()),
+        None    => AppError::NotFound("Room not found".into()),
+    })?;
+    if let Some((content, language)) = revision.filter(|_| !row.burn_after_reading) {
+        revisions::record(s, slug, &content, &language).await;
+    }
+    Ok(live.or_else(|| room::version(&s.rooms, slug)).unwrap_or(0).max(stored))
 }
 
 async fn delete_snippet(
     State(s): State<Arc<AppState>>,
     Path(slug): Path<String>,
 ) -> Result<impl IntoResponse, AppError> {
-    s.col().delete_one(doc! { "slug": &slug }, None).await.map_err(AppError::Db)?;
+    if let Some(row) = s.store.delete(&slug).await? {
+        close_room(&s, &slug, &WsMsg::Expired);
+        delete_blobs(&s, &row).await;
+    }
     Ok(StatusCode::NO_CONTENT)
 }
 
-// ── Upload a file (any type) to Cloudinary as a raw resource ──────────────────
-async fn upload_file(mut multipart: Multipart) -> Result<Json<FileData>, AppError> {
-    let cloud_name    = std::env::var("CLOUDINARY_CLOUD_NAME").unwrap();
-    let upload_preset = std::env::var("CLOUDINARY_UPLOAD_PRESET").unwrap();
-
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let original_name = field
-            .file_name()
-            .unwrap_or("file")
-            .to_string();
-        let mime_type = field
-            .content_type()
-            .unwrap_or("application/octet-stream")
-            .to_string();
-
-        let data = field.bytes().await.unwrap();
-        let size = data.len() as u64;
-
-        let form = reqwest::multipart::Form::new()
-            .part("file", reqwest::multipart::Part::bytes(data.to_vec())
-                .file_name(original_name.clone()))
-            .text("upload_preset", upload_preset)
-            .text("resource_type", "raw");
-
-        let url = format!(
-            "https://api.cloudinary.com/v1_1/{}/raw/upload",
-            cloud_name
-        );
-        let res  = reqwest::Client::new().post(url).multipart(form).send().await.unwrap();
-        let json: serde_json::Value = res.json().await.unwrap();
-
-        return Ok(Json(FileData {
-            id:   uuid::Uuid::new_v4().to_string(),
-            name: original_name,
-            url:  json["secure_url"].as_str().unwrap().to_string(),
-            size,
-            mime: mime_type,
-        }));
-    }
-    Err(AppError::BadRequest("No file provided".into()))
+// ── Upload a file (any type) to the blob store ────────────────────────────────
+async fn upload_file(
+    State(s): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Json<FileData>, AppError> {
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("multipart: {e}")))?
+        .ok_or_else(|| AppError::BadRequest("No file provided".into()))?;
+
+    let original_name = field
+        .file_name()
+        .unwrap_or("file")
+        .to_string();
+    let mime_type = field
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    // Stream the body straight through to the blob store instead of buffering it here.
+    let body = field.map_err(std::io::Error::other).boxed();
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let (url, size) = s.blobs
+        .put_stream(&id, BlobKind::Raw, original_name.clone(), mime_type.clone(), body)
+        .await?;
+    s.store.issue_blob(&url).await?;
+
+    Ok(Json(FileData { id, name: original_name, url, size, mime: mime_type }))
 }
 
 // ── Download all files in a room as a ZIP ─────────────────────────────────────
@@ -350,15 +660,12 @@ async fn download_zip(
     State(s): State<Arc<AppState>>,
     Path(slug): Path<String>,
 ) -> Result<Response, AppError> {
-    let row = s.col()
-        .find_one(doc! { "slug": &slug }, None)
-        .await
-        .map_err(AppError::Db)?
-        .ok_or_else(|| AppError::NotFound("Room not found".into()))?;
+    let row = load_live(&s, &slug).await?;
 
     if row.files.is_empty() {
         return Err(AppError::BadRequest("No files to download".into()));
     }
+    count_view(&s, &slug).await?;
 
     let buf    = Vec::new();
     let cursor = std::io::Cursor::new(buf);
@@ -366,17 +673,8 @@ async fn download_zip(
     let options = FileOptions::default()
         .compression_method(zip::CompressionMethod::Deflated);
 
-    let http = reqwest::Client::new();
-
     for file in &row.files {
-        let bytes = http
-            .get(&file.url)
-            .send()
-            .await
-            .map_err(|e| AppError::Internal(format!("fetch failed: {e}")))?
-            .bytes()
-            .await
-            .map_err(|e| AppError::Internal(format!("read failed: {e}")))?;
+        let bytes = s.blobs.fetch(&file.url).await?;
 
         zip.start_file(&file.name, options)
             .map_err(|e| AppError::Internal(format!("zip error: {e}")))?;
@@ -402,34 +700,24 @@ async fn download_zip(
         .unwrap())
 }
 
-// ── Proxy: stream a single file from Cloudinary with correct headers ──────────
+// ── Proxy: stream a single file from the blob store with correct headers ──────
 // This avoids CORS issues and ensures the browser receives Content-Disposition.
 async fn proxy_file(
     State(s): State<Arc<AppState>>,
     Path((slug, file_id)): Path<(String, String)>,
 ) -> Result<Response, AppError> {
-    // Look up the file metadata in MongoDB
-    let row = s.col()
-        .find_one(doc! { "slug": &slug }, None)
-        .await
-        .map_err(AppError::Db)?
-        .ok_or_else(|| AppError::NotFound("Room not found".into()))?;
+    // Look up the file metadata in the snippet store
+    let row = load_live(&s, &slug).await?;
 
     let file = row
         .files
         .into_iter()
         .find(|f| f.id == file_id)
         .ok_or_else(|| AppError::NotFound("File not found".into()))?;
+    count_view(&s, &slug).await?;
 
-    // Fetch the actual bytes from Cloudinary server-side
-    let bytes = reqwest::Client::new()
-        .get(&file.url)
-        .send()
-        .await
-        .map_err(|e| AppError::Internal(format!("fetch failed: {e}")))?
-        .bytes()
-        .await
-        .map_err(|e| AppError::Internal(format!("read body failed: {e}")))?;
+    // Stream the actual bytes from the blob store
+    let blob = s.blobs.open(&file.url).await?;
 
     // Encode filename for Content-Disposition (handles spaces, unicode, etc.)
     let encoded_name = urlencoding::encode(&file.name);
@@ -439,142 +727,386 @@ async fn proxy_file(
         encoded_name,
     );
 
-    Ok(Response::builder()
+    let mut res = Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_TYPE,        file.mime)
         .header(header::CONTENT_DISPOSITION, disposition)
-        .header(header::CONTENT_LENGTH,      bytes.len().to_string())
         // Allow the Vercel frontend to read this response
+        .header("Access-Control-Allow-Origin", "*");
+    if let Some(len) = blob.len {
+        res = res.header(header::CONTENT_LENGTH, len.to_string());
+    }
+    Ok(res.body(Body::from_stream(blob.stream)).unwrap())
+}
+
+// ── Image upload ──────────────────────────────────────────────────────────────
+async fn upload_image(
+    State(s): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Json<ImageData>, AppError> {
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("multipart: {e}")))?
+        .ok_or_else(|| AppError::BadRequest("No file".into()))?;
+
+    let name = field.file_name().unwrap_or("image").to_string();
+    let mime = field.content_type().unwrap_or("application/octet-stream").to_string();
+    let data = field.bytes().await
+        .map_err(|e| AppError::BadRequest(format!("multipart: {e}")))?;
+    let dims = imagesize::blob_size(&data)
+        .map_err(|_| AppError::BadRequest("Unsupported image format".into()))?;
+
+    let id  = uuid::Uuid::new_v4().to_string();
+    let url = s.blobs.put(&id, BlobKind::Image, Blob { name, mime, data }).await?;
+    s.store.issue_blob(&url).await?;
+
+    Ok(Json(ImageData {
+        id,
+        url,
+        width:  dims.width as u32,
+        height: dims.height as u32,
+    }))
+}
+
+// ── Serve a blob kept by this backend (local disk) ────────────────────────────
+/// Types `get_blob` lets a browser display rather than download.
+const INLINE_MIME: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+async fn get_blob(
+    State(s): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let blob = s.blobs
+        .read(&id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Blob not found".into()))?;
+
+    // The name and type are whatever the uploader sent, so only raster images
+    // are shown in place; anything else, SVG and HTML included, downloads.
+    let inline = INLINE_MIME.contains(&blob.mime.as_str());
+    let disposition = format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        if inline { "inline" } else { "attachment" },
+        blob.name.replace('"', "\\\""),
+        urlencoding::encode(&blob.name),
+    );
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE,        blob.mime)
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .header(header::CONTENT_LENGTH,      blob.data.len().to_string())
+        .header(header::CACHE_CONTROL,       "public, max-age=31536000, immutable")
+        .header(header::X_CONTENT_TYPE_OPTIONS,  "nosniff")
+        .header(header::CONTENT_SECURITY_POLICY, "sandbox")
         .header("Access-Control-Allow-Origin", "*")
-        .body(Body::from(bytes))
+        .body(Body::from(blob.data))
         .unwrap())
 }
 
-// ── Image upload ──────────────────────────────────────────────────────────────
-async fn upload_image(mut multipart: Multipart) -> Result<Json<ImageData>, AppError> {
-    let cloud_name    = std::env::var("CLOUDINARY_CLOUD_NAME").unwrap();
-    let upload_preset = std::env::var("CLOUDINARY_UPLOAD_PRESET").unwrap();
-
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let data = field.bytes().await.unwrap();
-        let form = reqwest::multipart::Form::new()
-            .part("file", reqwest::multipart::Part::bytes(data.to_vec()))
-            .text("upload_preset", upload_preset);
-        let url  = format!("https://api.cloudinary.com/v1_1/{}/image/upload", cloud_name);
-        let res  = reqwest::Client::new().post(url).multipart(form).send().await.unwrap();
-        let json: serde_json::Value = res.json().await.unwrap();
-        return Ok(Json(ImageData {
-            id:     uuid::Uuid::new_v4().to_string(),
-            url:    json["secure_url"].as_str().unwrap().to_string(),
-            width:  json["width"].as_u64().unwrap() as u32,
-            height: json["height"].as_u64().unwrap() as u32,
-        }));
-    }
-    Err(AppError::BadRequest("No file".into()))
+// ── Participants ──────────────────────────────────────────────────────────────
+async fn list_participants(
+    State(s): State<Arc<AppState>>,
+    Path(slug): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    load_live(&s, &slug).await?;
+    let roster = s.rooms.get(&slug).map(|r| r.roster()).unwrap_or_default();
+    Ok(Json(roster))
+}
+
+// ── Room registry ─────────────────────────────────────────────────────────────
+/// `GET /api/rooms`: stats for every open room. Needs `ADMIN_TOKEN` as a
+/// bearer token, and does not exist without one.
+async fn list_rooms(
+    State(s): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(token) = &s.config.admin_token else {
+        return Err(AppError::NotFound("Not found".into()));
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if given != Some(token.as_str()) {
+        return Err(AppError::Forbidden("Admin token required".into()));
+    }
+    Ok(Json(room::stats(&s.rooms)))
 }
 
 // ── WebSocket ─────────────────────────────────────────────────────────────────
+#[derive(Deserialize)]
+struct WsQuery {
+    #[serde(default)]
+    feed:  Feed,
+    /// Display name and `#rrggbb` colour; both are made up when missing.
+    name:  Option<String>,
+    color: Option<String>,
+    /// From a previous `Connected`, and the last `seq` seen since, to resume.
+    epoch: Option<u64>,
+    since: Option<u64>,
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     Path(slug): Path<String>,
+    Query(q): Query<WsQuery>,
     State(s): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_ws(socket, slug, s))
+) -> Result<Response, AppError> {
+    // Only refuse rooms that exist and have run out; unknown slugs still
+    // connect so a room can be opened before its first save.
+    let row = s.store.get(&slug).await?;
+    if let Some(row) = &row {
+        if row.is_expired() {
+            return Err(AppError::Gone("Room has expired".into()));
+        }
+        // A live session would read the content without burning it.
+        if row.burn_after_reading {
+            return Err(AppError::Forbidden("Burn-after-reading snippets cannot be joined".into()));
+        }
+    }
+    Ok(ws.on_upgrade(move |socket| handle_ws(socket, slug, s, q)))
 }
 
-async fn handle_ws(socket: WebSocket, slug: String, state: Arc<AppState>) {
-    let tx      = get_or_create_room(&state.rooms, &slug);
-    let mut rx  = tx.subscribe();
-    let viewers = tx.receiver_count();
+async fn handle_ws(socket: WebSocket, slug: String, state: Arc<AppState>, q: WsQuery) {
+    // Read the row again: an edit made over REST during the upgrade would
+    // otherwise be written over by the room's first flush.
+    let row = match state.store.get(&slug).await {
+        Ok(row) if row.as_ref().is_some_and(|r| r.is_expired() || r.burn_after_reading) => return,
+        Ok(row) => row,
+        Err(e) => {
+            warn!("failed to load /{slug} for a socket: {e:?}");
+            return;
+        }
+    };
+    let (room, mut rx) = get_or_create_room(&state, &slug, row.as_ref());
+    room.ready().await;
+    let conn    = crdt::new_site();
+    let feed    = q.feed;
     let (mut sender, mut receiver) = socket.split();
 
+    room.join(conn, feed, q.name, q.color.as_deref());
+    let viewers = room.viewers();
+    let resumed = q.since
+        .filter(|_| q.epoch == Some(room.epoch))
+        .and_then(|since| room.resume(since, feed));
+    let (version, seq, missed, snapshot) = match resumed {
+        Some((version, seq, missed)) => (version, seq, missed, None),
+        None => {
+            let snap = room.snapshot(feed);
+            (snap.version, snap.seq, Vec::new(), Some(snap))
+        }
+    };
+    // Loaded after the snapshot, so a message sent in between shows up twice
+    // rather than not at all.
+    let chat = chat::history(&state, &slug).await;
     let _ = sender.send(Message::Text(
-        serde_json::to_string(&WsMsg::Connected { slug: slug.clone(), viewers }).unwrap().into(),
+        serde_json::to_string(&WsMsg::Connected {
+            slug: slug.clone(), viewers, conn, version, epoch: room.epoch, seq,
+            resumed:      snapshot.is_none(),
+            participants: room.roster(),
+            presence:     room.presence(),
+            chat,
+            state:   snapshot.as_ref().and_then(|s| s.state.clone()),
+            content: snapshot.as_ref().filter(|_| feed == Feed::Patch).map(|s| s.content.clone()),
+        }).unwrap(),
     )).await;
-    let _ = tx.send(serde_json::to_string(&WsMsg::Viewers { count: viewers + 1 }).unwrap());
+    for frame in missed {
+        let _ = sender.send(Message::Text(frame.text)).await;
+    }
+    if let Some(snap) = snapshot.filter(|_| q.since.is_some()) {
+        let _ = sender.send(Message::Text(serde_json::to_string(&resync(&state, &slug, &room, snap).await).unwrap())).await;
+    }
+    room.send_viewers();
+
+    // Replies meant for this socket alone, such as a refused patch.
+    let (reply, mut replies) = tokio::sync::mpsc::unbounded_channel::<WsMsg>();
+    // When anything, pongs included, last arrived from the client.
+    let (heard, heard_at) = tokio::sync::watch::channel(tokio::time::Instant::now());
+    let mut keepalive = Keepalive::new(&state.config, heard_at);
 
     let slug2  = slug.clone();
     let state2 = state.clone();
-    let tx2    = tx.clone();
+    let room2  = room.clone();
 
     let mut recv_task = tokio::spawn(async move {
-        let col = state2.col();
-        while let Some(Ok(Message::Text(text))) = receiver.next().await {
+        let store = &state2.store;
+        while let Some(Ok(frame)) = receiver.next().await {
+            heard.send_replace(tokio::time::Instant::now());
+            let text = match frame {
+                Message::Text(text) => text,
+                Message::Close(_)   => break,
+                // Pings are answered by axum itself.
+                _                   => continue,
+            };
             let msg: WsMsg = match serde_json::from_str(&text) { Ok(m) => m, Err(_) => continue };
             match msg {
-                WsMsg::Edit { ref content, ref language } => {
-                    let _ = col.update_one(
-                        doc! { "slug": &slug2 },
-                        doc! { "$set": { "content": content, "language": language } },
-                        None,
-                    ).await;
-                    let _ = tx2.send(serde_json::to_string(&WsMsg::BroadcastEdit {
-                        content: content.clone(), language: language.clone(),
-                    }).unwrap());
+                WsMsg::Edit { content, language } => {
+                    room::edit(&state2, &slug2, conn, content, language);
+                }
+                WsMsg::Ops { ops } => {
+                    room::apply_ops(&state2, &slug2, conn, ops);
+                }
+                WsMsg::Patch { base, ops } => {
+                    if let Err(r) = room::apply_patch(&state2, &slug2, conn, base, ops) {
+                        let _ = reply.send(WsMsg::PatchRejected { base, version: r.version, content: r.content });
+                    }
                 }
+                WsMsg::Cursor { ranges } => room2.set_cursor(conn, ranges),
+                WsMsg::Rename { name, color } => room2.rename(conn, name, color.as_deref()),
+                WsMsg::Chat { text } => chat::post(&state2, &room2, &slug2, conn, text).await,
                 WsMsg::Image { ref image } => {
-                    let row = col.find_one(doc! { "slug": &slug2 }, None).await.ok().flatten();
-                    let mut imgs: Vec<ImageData> = row.map(|r| r.images).unwrap_or_default();
-                    if !imgs.iter().any(|i| i.id == image.id) { imgs.push(image.clone()); }
-                    let _ = col.update_one(
-                        doc! { "slug": &slug2 },
-                        doc! { "$set": { "images": to_bson(&imgs).unwrap() } },
-                        None,
-                    ).await;
-                    let _ = tx2.send(serde_json::to_string(&WsMsg::BroadcastImage { image: image.clone() }).unwrap());
+                    // Same rule as the REST attach: never another snippet's upload.
+                    if !matches!(store.claim_blob(&image.url, &slug2).await, Ok(true)) { continue; }
+                    if let Ok(true) = store.add_image(&slug2, image.clone()).await {
+                        room2.send(&WsMsg::BroadcastImage { image: image.clone() });
+                    }
                 }
                 WsMsg::RemoveImage { ref id } => {
-                    let row = col.find_one(doc! { "slug": &slug2 }, None).await.ok().flatten();
-                    let mut imgs: Vec<ImageData> = row.map(|r| r.images).unwrap_or_default();
-                    imgs.retain(|i| &i.id != id);
-                    let _ = col.update_one(
-                        doc! { "slug": &slug2 },
-                        doc! { "$set": { "images": to_bson(&imgs).unwrap() } },
-                        None,
-                    ).await;
-                    let _ = tx2.send(serde_json::to_string(&WsMsg::BroadcastRemoveImage { id: id.clone() }).unwrap());
+                    if let Ok(true) = store.remove_image(&slug2, id).await {
+                        room2.send(&WsMsg::BroadcastRemoveImage { id: id.clone() });
+                    }
                 }
                 WsMsg::File { ref file } => {
-                    let row = col.find_one(doc! { "slug": &slug2 }, None).await.ok().flatten();
-                    let mut files: Vec<FileData> = row.map(|r| r.files).unwrap_or_default();
-                    if !files.iter().any(|f| f.id == file.id) { files.push(file.clone()); }
-                    let _ = col.update_one(
-                        doc! { "slug": &slug2 },
-                        doc! { "$set": { "files": to_bson(&files).unwrap() } },
-                        None,
-                    ).await;
-                    let _ = tx2.send(serde_json::to_string(&WsMsg::BroadcastFile { file: file.clone() }).unwrap());
+                    if !matches!(store.claim_blob(&file.url, &slug2).await, Ok(true)) { continue; }
+                    if let Ok(true) = store.add_file(&slug2, file.clone()).await {
+                        room2.send(&WsMsg::BroadcastFile { file: file.clone() });
+                    }
                 }
                 WsMsg::RemoveFile { ref id } => {
-                    let row = col.find_one(doc! { "slug": &slug2 }, None).await.ok().flatten();
-                    let mut files: Vec<FileData> = row.map(|r| r.files).unwrap_or_default();
-                    files.retain(|f| &f.id != id);
-                    let _ = col.update_one(
-                        doc! { "slug": &slug2 },
-                        doc! { "$set": { "files": to_bson(&files).unwrap() } },
-                        None,
-                    ).await;
-                    let _ = tx2.send(serde_json::to_string(&WsMsg::BroadcastRemoveFile { id: id.clone() }).unwrap());
+                    if let Ok(true) = store.remove_file(&slug2, id).await {
+                        room2.send(&WsMsg::BroadcastRemoveFile { id: id.clone() });
+                    }
                 }
                 _ => {}
             }
         }
     });
 
+    let (slug3, state3, room3) = (slug.clone(), state.clone(), room.clone());
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg.into())).await.is_err() { break; }
+        let limit = keepalive.write_timeout();
+        // The last broadcast the client has, from the handshake or since.
+        // Frames the channel holds from before it are skipped.
+        let mut seen = seq;
+        'send: loop {
+            let (msg, last) = tokio::select! {
+                frame = rx.recv() => match frame {
+                    Ok(frame) if frame.seq <= seen => continue,
+                    Ok(frame) if frame.feed.is_none_or(|f| f == feed) => {
+                        seen = frame.seq;
+                        (frame.text, frame.last)
+                    }
+                    Ok(_)  => continue,
+                    Err(RecvError::Lagged(n)) => {
+                        room3.lagged(n);
+                        // Start over from frames sent from here on, and fill
+                        // the gap from the replay buffer if it still can.
+                        rx = rx.resubscribe();
+                        if let Some((_, upto, missed)) = room3.resume(seen, feed) {
+                            warn!("ws /{slug3} ({conn}) fell {n} frames behind; replaying");
+                            for frame in missed {
+                                if !send_within(&mut sender, Message::Text(frame.text), limit).await {
+                                    room3.reaped();
+                                    break 'send;
+                                }
+                            }
+                            seen = upto;
+                            continue;
+                        }
+                        warn!("ws /{slug3} ({conn}) fell {n} frames behind; resyncing");
+                        let snap = room3.snapshot(feed);
+                        seen = snap.seq;
+                        (serde_json::to_string(&resync(&state3, &slug3, &room3, snap).await).unwrap(), false)
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                Some(msg) = replies.recv() => (serde_json::to_string(&msg).unwrap(), false),
+                beat = keepalive.tick() => match beat {
+                    Beat::Ping => {
+                        if !send_within(&mut sender, Message::Ping(Vec::new()), limit).await {
+                            room3.reaped();
+                            break;
+                        }
+                        continue;
+                    }
+                    Beat::Dead(why) => {
+                        info!("ws /{slug3} ({conn}) dropped: {why}");
+                        room3.reaped();
+                        break;
+                    }
+                    Beat::Alive => continue,
+                },
+            };
+            if !send_within(&mut sender, Message::Text(msg), limit).await {
+                room3.reaped();
+                break;
+            }
+            if last {
+                let _ = sender.send(Message::Close(None)).await;
+                break;
+            }
         }
     });
 
+    // Wait for the other half to be dropped so the receiver count below no
+    // longer includes this socket.
     tokio::select! {
-        _ = &mut recv_task => send_task.abort(),
-        _ = &mut send_task => recv_task.abort(),
+        _ = &mut recv_task => { send_task.abort(); let _ = send_task.await; }
+        _ = &mut send_task => { recv_task.abort(); let _ = recv_task.await; }
     }
 
-    let remaining = tx.receiver_count().saturating_sub(1);
-    let _ = tx.send(serde_json::to_string(&WsMsg::Viewers { count: remaining }).unwrap());
-    info!("ws disconnected /{slug}");
+    room.leave(conn, feed);
+    if room.tx.receiver_count() == 0 {
+        room::release(&state, &slug, &room).await;
+    }
+    info!("ws disconnected /{slug} ({conn})");
+}
+
+/// Writes one frame, giving up on a client that does not take it within
+/// `limit`: a peer that stopped reading would otherwise hold the socket open
+/// for as long as TCP keeps retrying.
+async fn send_within(sender: &mut SplitSink<WebSocket, Message>, msg: Message, limit: std::time::Duration) -> bool {
+    matches!(tokio::time::timeout(limit, sender.send(msg)).await, Ok(Ok(())))
+}
+
+/// What a socket that missed more frames than the room still holds gets
+/// instead of them, built around `snap`.
+async fn resync(s: &AppState, slug: &str, room: &room::Room, snap: room::Snapshot) -> WsMsg {
+    let (images, files) = match s.store.get(slug).await {
+        Ok(Some(row)) => (row.images, row.files),
+        _             => Default::default(),
+    };
+    let room::Snapshot { version, seq, content, language, state } = snap;
+    WsMsg::Resync {
+        version, seq, content, language, state,
+        participants: room.roster(),
+        presence:     room.presence(),
+        images,
+        files,
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c    => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
 }
 
 // ── main ──────────────────────────────────────────────────────────────────────
@@ -597,10 +1129,22 @@ async fn main() {
     let frontend = std::env::var("FRONTEND_URL")
         .unwrap_or_else(|_| "http://localhost:5173".into());
 
-    let db = db::get_database().await;
+    // Base URL this backend is reachable at; blobs served from disk link back here.
+    let public_url = std::env::var("PUBLIC_URL")
+        .unwrap_or_else(|_| format!("http://localhost:{port}"));
+
+    let store = db::connect().await;
     info!("db ready");
 
-    let state = Arc::new(AppState { db, rooms: Arc::new(DashMap::new()) });
+    let blobs = blob::connect(&public_url).await;
+
+    let config = Arc::new(Config::from_env());
+
+    let (fanout, incoming) = fanout::connect();
+
+    let state = Arc::new(AppState { store, blobs, rooms: Arc::new(DashMap::new()), config, fanout });
+    reaper::spawn(state.clone());
+    fanout::spawn(state.clone(), incoming);
 
     // ── CORS: explicit methods + headers so Render's proxy doesn't strip them ──
     let cors = CorsLayer::new()
@@ -620,7 +1164,9 @@ async fn main() {
             HeaderName::from_static("content-type"),
             HeaderName::from_static("authorization"),
             HeaderName::from_static("x-requested-with"),
-        ]);
+            header::IF_MATCH,
+        ])
+        .expose_headers([header::ETAG]);
 
     // ── Router: chain methods on the same path — do NOT use separate .route() ──
     //    calls for the same path because each one silently replaces the previous.
@@ -628,6 +1174,7 @@ async fn main() {
         .route("/health",                          get(health))
         .route("/api/upload",                      post(upload_image))
         .route("/api/upload-file",                 post(upload_file))
+        .route("/api/blobs/:id",                   get(get_blob))
         .route("/api/check/:slug",                 get(check_slug))
         .route("/api/snippets",                    post(create_snippet))
         // ↓ FIXED: get/patch/delete chained on the same path
@@ -636,13 +1183,93 @@ async fn main() {
             get(get_snippet).patch(patch_snippet).delete(delete_snippet),
         )
         .route("/api/snippets/:slug/download-zip",          get(download_zip))
-        .route("/api/snippets/:slug/files/:file_id",        get(proxy_file))
+        .route("/api/snippets/:slug/images",                post(attachments::attach_image))
+        .route("/api/snippets/:slug/images/:id",            delete(attachments::detach_image))
+        .route("/api/snippets/:slug/files",                 post(attachments::attach_file))
+        .route("/api/snippets/:slug/files/:file_id",        get(proxy_file).delete(attachments::detach_file))
+        .route("/api/snippets/:slug/revisions",             get(revisions::list_revisions))
+        .route("/api/snippets/:slug/revisions/:rev",        get(revisions::get_revision))
+        .route("/api/snippets/:slug/revisions/:rev/restore", post(revisions::restore_revision))
+        .route("/api/snippets/:slug/diff",                  get(diff::diff_revisions))
+        .route("/api/snippets/:slug/participants",          get(list_participants))
+        .route("/api/diff/:a/:b",                  get(diff::diff_slugs))
+        .route("/api/rooms",                       get(list_rooms))
         .route("/ws/:slug",                        get(ws_handler))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
-        .with_state(state);
+        .with_state(state.clone());
 
     let addr = format!("0.0.0.0:{port}");
     info!("listening on http://{addr}");
-    axum::serve(tokio::net::TcpListener::bind(&addr).await.unwrap(), app).await.unwrap();
-}
\ No newline at end of file
+    axum::serve(tokio::net::TcpListener::bind(&addr).await.unwrap(), app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // Edits still only held in memory go out before the process exits.
+    room::flush_all(&state).await;
+    info!("open rooms flushed");
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How far past now `raw` lands, give or take the time the call took.
+    fn ahead(raw: &str) -> chrono::Duration {
+        parse_expiry(raw).unwrap() - Utc::now()
+    }
+
+    fn refused(raw: &str) -> bool {
+        matches!(parse_expiry(raw), Err(AppError::BadRequest(_)))
+    }
+
+    #[test]
+    fn relative_expiries_count_from_now() {
+        let slack = chrono::Duration::seconds(5);
+        assert!((ahead("10m") - chrono::Duration::minutes(10)).abs() < slack);
+        assert!((ahead("1w") - chrono::Duration::weeks(1)).abs() < slack);
+        assert!((ahead(" 2h ") - chrono::Duration::hours(2)).abs() < slack);
+    }
+
+    #[test]
+    fn never_and_far_future_times_are_capped() {
+        assert_eq!(parse_expiry("never").unwrap(), never());
+        assert_eq!(parse_expiry("NEVER").unwrap(), never());
+        assert_eq!(parse_expiry("3000-01-01T00:00:00Z").unwrap(), never());
+        assert_eq!(parse_expiry("100000w").unwrap(), never());
+    }
+
+    #[test]
+    fn future_rfc3339_times_are_kept() {
+        let at = Utc::now() + chrono::Duration::days(3);
+        let parsed = parse_expiry(&at.to_rfc3339()).unwrap();
+        assert!((parsed - at).abs() < chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn past_times_are_refused() {
+        assert!(refused("2001-01-01T00:00:00Z"));
+        assert!(refused(&(Utc::now() - chrono::Duration::minutes(1)).to_rfc3339()));
+    }
+
+    #[test]
+    fn malformed_expiries_are_refused() {
+        for raw in ["0h", "-1h", "1x", "h", "", "1.5h", "1 h"] {
+            assert!(refused(raw), "{raw:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn multibyte_suffixes_are_refused_without_panicking() {
+        for raw in ["1é", "é", "10分", "🙂"] {
+            assert!(refused(raw), "{raw:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn overflowing_amounts_are_refused() {
+        assert!(refused("99999999999999999999m"));
+        assert!(refused(&format!("{}w", i64::MAX)));
+        assert!(refused(&format!("{}m", i64::MAX)));
+    }
+}
@@ -0,0 +1,188 @@
+//! Position-based text edits and their transformation, for clients that send
+//! plain insert/delete patches against a numbered version of a room.
+//!
+//! Positions count Unicode characters, not bytes or UTF-16 units. A list of
+//! operations applies in order, each to the text the previous one produced.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TextOp {
+    Insert { pos: usize, text: String },
+    Delete { pos: usize, len: usize },
+}
+
+/// Checks that `ops` apply cleanly to a text of `len` characters.
+pub fn fits(ops: &[TextOp], mut len: usize) -> bool {
+    for op in ops {
+        match op {
+            TextOp::Insert { pos, text } => {
+                if *pos > len || text.is_empty() { return false; }
+                len += text.chars().count();
+            }
+            TextOp::Delete { pos, len: n } => {
+                if *n == 0 || pos.checked_add(*n).is_none_or(|end| end > len) { return false; }
+                len -= n;
+            }
+        }
+    }
+    true
+}
+
+/// Transforms two operation lists made concurrently against the same text.
+/// Returns `(a', b')` such that applying `b` then `a'` gives the same text as
+/// `a` then `b'`. When both insert at the same position, `b`'s text goes first.
+pub fn transform(a: &[TextOp], b: &[TextOp]) -> (Vec<TextOp>, Vec<TextOp>) {
+    match (a, b) {
+        ([], _) | (_, []) => (a.to_vec(), b.to_vec()),
+        ([a], [b]) => transform_one(a, b),
+        ([first, rest @ ..], _) if !rest.is_empty() => {
+            let (first, b)  = transform(std::slice::from_ref(first), b);
+            let (rest, b)   = transform(rest, &b);
+            ([first, rest].concat(), b)
+        }
+        (_, [first, rest @ ..]) => {
+            let (a, first)  = transform(a, std::slice::from_ref(first));
+            let (a, rest)   = transform(&a, rest);
+            (a, [first, rest].concat())
+        }
+    }
+}
+
+fn transform_one(a: &TextOp, b: &TextOp) -> (Vec<TextOp>, Vec<TextOp>) {
+    use TextOp::{Delete, Insert};
+    match (a, b) {
+        (Insert { pos: pa, text: ta }, Insert { pos: pb, text: tb }) => {
+            let (la, lb) = (ta.chars().count(), tb.chars().count());
+            let a2 = Insert { pos: if pb <= pa { pa + lb } else { *pa }, text: ta.clone() };
+            let b2 = Insert { pos: if pa <  pb { pb + la } else { *pb }, text: tb.clone() };
+            (vec![a2], vec![b2])
+        }
+        (Insert { pos, text }, Delete { pos: dp, len: dl }) => insert_vs_delete(*pos, text, *dp, *dl),
+        (Delete { pos: dp, len: dl }, Insert { pos, text }) => {
+            let (ins, del) = insert_vs_delete(*pos, text, *dp, *dl);
+            (del, ins)
+        }
+        (Delete { pos: pa, len: la }, Delete { pos: pb, len: lb }) => {
+            (delete_after_delete(*pa, *la, *pb, *lb), delete_after_delete(*pb, *lb, *pa, *la))
+        }
+    }
+}
+
+/// An insert at `ip` against a concurrent delete of `[dp, dp + dl)`. Returns
+/// the insert as it applies after the delete, and the delete as it applies
+/// after the insert.
+fn insert_vs_delete(ip: usize, text: &str, dp: usize, dl: usize) -> (Vec<TextOp>, Vec<TextOp>) {
+    let il = text.chars().count();
+    if ip <= dp {
+        (vec![TextOp::Insert { pos: ip, text: text.to_string() }], vec![TextOp::Delete { pos: dp + il, len: dl }])
+    } else if ip >= dp + dl {
+        (vec![TextOp::Insert { pos: ip - dl, text: text.to_string() }], vec![TextOp::Delete { pos: dp, len: dl }])
+    } else {
+        // The insert lands inside the deleted range: it survives at the range's
+        // start, and the delete is split around it.
+        let head = ip - dp;
+        (
+            vec![TextOp::Insert { pos: dp, text: text.to_string() }],
+            vec![TextOp::Delete { pos: dp, len: head }, TextOp::Delete { pos: dp + il, len: dl - head }],
+        )
+    }
+}
+
+/// A delete of `[p, p + l)` as it applies after `[q, q + m)` is gone.
+fn delete_after_delete(p: usize, l: usize, q: usize, m: usize) -> Vec<TextOp> {
+    let shift = |x: usize| if x <= q { x } else if x >= q + m { x - m } else { q };
+    let (start, end) = (shift(p), shift(p + l));
+    if end > start { vec![TextOp::Delete { pos: start, len: end - start }] } else { vec![] }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TextOp::{Delete, Insert};
+
+    fn apply(text: &str, ops: &[TextOp]) -> String {
+        let mut chars: Vec<char> = text.chars().collect();
+        for op in ops {
+            match op {
+                Insert { pos, text } => { chars.splice(*pos..*pos, text.chars()); }
+                Delete { pos, len }  => { chars.drain(*pos..pos + len); }
+            }
+        }
+        chars.into_iter().collect()
+    }
+
+    fn ins(pos: usize, text: &str) -> TextOp {
+        Insert { pos, text: text.into() }
+    }
+
+    fn del(pos: usize, len: usize) -> TextOp {
+        Delete { pos, len }
+    }
+
+    /// Both orders give the same text, which is returned.
+    fn converge(text: &str, a: &[TextOp], b: &[TextOp]) -> String {
+        assert!(fits(a, text.chars().count()) && fits(b, text.chars().count()));
+        let (a2, b2) = transform(a, b);
+        let ab = apply(&apply(text, b), &a2);
+        assert_eq!(ab, apply(&apply(text, a), &b2), "a = {a:?}, b = {b:?}");
+        ab
+    }
+
+    #[test]
+    fn insert_insert() {
+        assert_eq!(converge("hello", &[ins(1, "X")], &[ins(4, "Y")]), "hXellYo");
+        assert_eq!(converge("hello", &[ins(4, "X")], &[ins(1, "Y")]), "hYellXo");
+    }
+
+    #[test]
+    fn insert_delete() {
+        assert_eq!(converge("hello", &[ins(0, "X")], &[del(1, 2)]), "Xhlo");
+        assert_eq!(converge("hello", &[ins(5, "X")], &[del(1, 2)]), "hloX");
+    }
+
+    #[test]
+    fn delete_insert() {
+        assert_eq!(converge("hello", &[del(1, 2)], &[ins(0, "X")]), "Xhlo");
+        assert_eq!(converge("hello", &[del(0, 2)], &[ins(4, "X")]), "llXo");
+    }
+
+    #[test]
+    fn delete_delete() {
+        assert_eq!(converge("hello", &[del(0, 2)], &[del(3, 2)]), "l");
+        assert_eq!(converge("hello", &[del(0, 3)], &[del(1, 3)]), "o");
+        assert_eq!(converge("hello", &[del(1, 2)], &[del(1, 2)]), "hlo");
+        assert_eq!(converge("hello", &[del(0, 5)], &[del(2, 1)]), "");
+    }
+
+    #[test]
+    fn insert_inside_deleted_range_splits_the_delete() {
+        let (a2, b2) = transform(&[del(1, 3)], &[ins(2, "XY")]);
+        assert_eq!(b2, vec![ins(1, "XY")]);
+        assert_eq!(a2, vec![del(1, 1), del(3, 2)]);
+        assert_eq!(converge("hello", &[del(1, 3)], &[ins(2, "XY")]), "hXYo");
+        assert_eq!(converge("hello", &[ins(2, "XY")], &[del(1, 3)]), "hXYo");
+    }
+
+    #[test]
+    fn same_position_inserts_put_b_first() {
+        assert_eq!(converge("ab", &[ins(1, "A")], &[ins(1, "B")]), "aBAb");
+        assert_eq!(converge("ab", &[ins(1, "B")], &[ins(1, "A")]), "aABb");
+        assert_eq!(converge("", &[ins(0, "x")], &[ins(0, "y")]), "yx");
+    }
+
+    #[test]
+    fn multi_op_lists() {
+        let a = [ins(0, ">"), del(3, 2), ins(4, "!")];
+        let b = [del(0, 1), ins(2, "--"), del(5, 1)];
+        converge("hello world", &a, &b);
+        converge("hello world", &b, &a);
+        converge("héllo wörld", &[del(1, 4), ins(1, "ÉÉ")], &[ins(3, "ü"), ins(0, "ß"), del(6, 2)]);
+    }
+
+    #[test]
+    fn positions_count_characters() {
+        assert_eq!(converge("añb", &[ins(2, "é")], &[del(0, 1)]), "ñéb");
+    }
+}
@@ -0,0 +1,55 @@
+use chrono::Utc;
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+use crate::{delete_blobs, room::{self, close_room}, AppState, WsMsg};
+
+/// Rows handled per sweep; anything beyond waits for the next tick.
+const BATCH: usize = 100;
+
+/// Spawns the periodic sweep that removes expired rooms: the row first, then
+/// any live WebSocket room gets a final `expired` message, then the uploads.
+/// Open rooms whose row went away some other way are closed too.
+/// The interval comes from `REAPER_INTERVAL_SECS` (default 60).
+pub fn spawn(state: Arc<AppState>) {
+    let every = std::env::var("REAPER_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(60);
+
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(every));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            sweep(&state).await;
+        }
+    });
+}
+
+async fn sweep(state: &AppState) {
+    let now = Utc::now();
+    let rows = match state.store.expired(now, BATCH).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            warn!("reaper: listing expired rooms failed: {e:?}");
+            return;
+        }
+    };
+
+    for row in rows {
+        // Uploads go by slug, so only once this row is surely gone: the slug
+        // may have been taken again since the listing.
+        match state.store.delete_if_expired(&row.slug, now).await {
+            Ok(Some(gone)) => {
+                close_room(state, &row.slug, &WsMsg::Expired);
+                delete_blobs(state, &gone).await;
+                info!("reaped /{}", row.slug);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("reaper: deleting /{} failed: {e:?}", row.slug),
+        }
+    }
+    room::evict_stale(state).await;
+}
@@ -0,0 +1,116 @@
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Json},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{crdt, error::AppError, load_for_read, load_live, refuse_burn, room, store::SnippetPatch, AppState};
+
+/// A snapshot of a room's content. Bursts of edits share one revision: while
+/// the latest revision is younger than the coalesce window it is updated in
+/// place rather than followed by a new one.
+#[derive(Debug, Serialize, Clone)]
+pub struct Revision {
+    pub slug:       String,
+    pub rev:        u64,
+    pub content:    String,
+    pub language:   String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What the revision list shows: everything but the content itself.
+#[derive(Debug, Serialize)]
+pub struct RevisionSummary {
+    pub rev:        u64,
+    pub language:   String,
+    pub size:       usize,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&Revision> for RevisionSummary {
+    fn from(r: &Revision) -> Self {
+        RevisionSummary {
+            rev:        r.rev,
+            language:   r.language.clone(),
+            size:       r.content.len(),
+            created_at: r.created_at,
+            updated_at: r.updated_at,
+        }
+    }
+}
+
+/// Records the room's current content as a revision. Failures are logged
+/// only; losing a history entry must not fail the edit that triggered it.
+pub async fn record(s: &AppState, slug: &str, content: &str, language: &str) {
+    let window = chrono::Duration::from_std(s.config.revision_coalesce).unwrap_or_default();
+    if let Err(e) = s.store.record_revision(slug, content, language, window).await {
+        tracing::warn!("failed to record revision for /{slug}: {e:?}");
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreResponse {
+    pub rev:           u64,
+    pub restored_from: u64,
+}
+
+// ── Handlers ──────────────────────────────────────────────────────────────────
+//
+// Burn-after-reading rooms have no history worth showing, and their first
+// revision would be the secret itself, so every handler here refuses them.
+
+pub async fn list_revisions(
+    State(s): State<Arc<AppState>>,
+    Path(slug): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    refuse_burn(&load_live(&s, &slug).await?)?;
+    let revs = s.store.list_revisions(&slug).await?;
+    Ok(Json(revs.iter().map(RevisionSummary::from).collect::<Vec<_>>()))
+}
+
+pub async fn get_revision(
+    State(s): State<Arc<AppState>>,
+    Path((slug, rev)): Path<(String, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    load_for_read(&s, &slug).await?;
+    let rev = s.store
+        .get_revision(&slug, rev)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Revision not found".into()))?;
+    Ok(Json(rev))
+}
+
+/// Makes an old revision current again. The restore is itself recorded as a
+/// fresh revision, never folded into the latest one, so it can be undone too.
+pub async fn restore_revision(
+    State(s): State<Arc<AppState>>,
+    Path((slug, rev)): Path<(String, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    refuse_burn(&load_live(&s, &slug).await?)?;
+    let old = s.store
+        .get_revision(&slug, rev)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Revision not found".into()))?;
+
+    let hold = room::hold(&s, &slug).await?;
+    let live = room::edit(&s, &slug, crdt::SERVER_SITE, old.content.clone(), old.language.clone());
+    room::unhold(&s, &slug, hold).await;
+    if !live {
+        s.store.patch(&slug, SnippetPatch {
+            content:  Some(old.content.clone()),
+            language: Some(old.language.clone()),
+            ..Default::default()
+        }).await?;
+    }
+    let new_rev = s.store
+        .record_revision(&slug, &old.content, &old.language, chrono::Duration::zero())
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".into()))?;
+
+    tracing::info!("restored /{slug} to revision {rev} as {new_rev}");
+    Ok(Json(RestoreResponse { rev: new_rev, restored_from: rev }))
+}
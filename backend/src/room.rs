@@ -0,0 +1,1075 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+
+use crate::{
+    crdt::{self, Applied, Op, Run},
+    error::AppError,
+    fanout::{Envelope, Event, Fanout},
+    ot::{self, TextOp},
+    revisions,
+    store::SnippetPatch,
+    AppState, SnippetRow, WsMsg,
+};
+
+/// How long a room opened while other instances may have it waits for one of
+/// them to send its copy before going on from the store.
+const SYNC_WAIT: Duration = Duration::from_millis(300);
+
+pub type Rooms = Arc<DashMap<String, Arc<Room>>>;
+
+/// Which form of document changes a socket follows, picked with `?feed=` on
+/// connect. Every other message goes to all sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Feed {
+    /// `BroadcastEdit` with the whole text; what older clients expect. These
+    /// are not replayed: a socket that misses one gets a `Resync` instead.
+    #[default]
+    Full,
+    /// `BroadcastOps` for CRDT replicas.
+    Ops,
+    /// `BroadcastPatch` with position-based edits and a version.
+    Patch,
+}
+
+/// A serialized `WsMsg` on its way to every socket in a room, or only to those
+/// following `feed`. `text` carries `seq` as a field of its own; the forms of
+/// one change for the different feeds share a number.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub feed: Option<Feed>,
+    pub seq:  u64,
+    pub text: String,
+    /// `WsMsg::Expired`, after which the socket is closed.
+    pub last: bool,
+}
+
+/// The room's latest frames, for sockets that come back after a drop or fall
+/// behind the channel.
+#[derive(Debug, Default)]
+struct Replay {
+    /// Number of the latest broadcast.
+    seq:        u64,
+    /// Latest number some of whose frames are no longer kept. Resuming from
+    /// before it would skip them.
+    evicted:    u64,
+    /// Number of the latest document change. `Feed::Full` frames carry the
+    /// whole text and are never kept, so full-feed sockets cannot resume from
+    /// before it.
+    changed:    u64,
+    /// Latest number before the document's ids were renumbered. `Feed::Ops`
+    /// sockets hold the old ids, so cannot resume from it or earlier.
+    renumbered: Option<u64>,
+    frames:     VecDeque<Frame>,
+}
+
+/// The whole document at one point, and the last broadcast it takes in.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub version:  u64,
+    pub seq:      u64,
+    pub content:  String,
+    pub language: String,
+    /// The replicated form, for `Feed::Ops` only.
+    pub state:    Option<Vec<Run>>,
+}
+
+/// One caret or selection, in characters like `ot::TextOp` positions. A bare
+/// caret has `anchor == head`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Range {
+    pub anchor: usize,
+    pub head:   usize,
+}
+
+/// Where one connection's carets are; the first range is the primary one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub conn:   u64,
+    pub ranges: Vec<Range>,
+}
+
+/// Someone in a room, as shown in the roster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Participant {
+    pub conn:      u64,
+    pub name:      String,
+    /// `#rrggbb`, for carets and avatars.
+    pub color:     String,
+    pub joined_at: DateTime<Utc>,
+}
+
+/// Handed out in turn to participants who do not pick a colour.
+const PALETTE: [&str; 8] = [
+    "#e06c75", "#61afef", "#98c379", "#c678dd", "#e5c07b", "#56b6c2", "#d19a66", "#be5046",
+];
+
+const MAX_NAME: usize = 40;
+
+fn display_name(raw: Option<String>, conn: u64) -> String {
+    let name: String = raw.as_deref().unwrap_or("").trim().chars().take(MAX_NAME).collect();
+    if name.is_empty() { format!("Guest {:04}", conn % 10_000) } else { name }
+}
+
+fn color(raw: Option<&str>, conn: u64) -> String {
+    match raw {
+        Some(c) if c.len() == 7 && c.starts_with('#') && c[1..].chars().all(|h| h.is_ascii_hexdigit()) => {
+            c.to_ascii_lowercase()
+        }
+        _ => PALETTE[(conn % PALETTE.len() as u64) as usize].to_string(),
+    }
+}
+
+/// Why a patch was refused, with what the client needs to start over.
+#[derive(Debug)]
+pub struct Rejection {
+    pub version: u64,
+    pub content: String,
+}
+
+/// A room's text while anyone is connected. Edits land here and reach the
+/// store in the background, at most one write per `WRITE_BEHIND_MS`.
+#[derive(Debug, Default)]
+struct LiveDoc {
+    text:          crdt::Doc,
+    language:      String,
+    /// Bumped by every change; what position-based patches are based on.
+    /// Carries on from the stored `SnippetRow::version` and is written back
+    /// with each flush.
+    version:       u64,
+    /// Edits that produced the latest versions, oldest first, for rebasing
+    /// patches made against them.
+    history:       VecDeque<Vec<TextOp>>,
+    /// Changed since the last successful flush.
+    dirty:         bool,
+    /// A delayed flush is already on its way.
+    flush_pending: bool,
+    /// Operations made here while the room waits for another instance's
+    /// copy, to be replayed on top of it. `None` once it has caught up.
+    pending:       Option<Vec<Op>>,
+}
+
+impl LiveDoc {
+    /// Brings a patch made against `base` up to date and applies it.
+    fn rebase(&mut self, base: u64, edits: &[TextOp]) -> Option<Applied> {
+        let behind = usize::try_from(self.version.checked_sub(base)?).ok()?;
+        let missed: Vec<TextOp> = self.history
+            .iter()
+            .skip(self.history.len().checked_sub(behind)?)
+            .flatten()
+            .cloned()
+            .collect();
+        let (edits, _) = ot::transform(edits, &missed);
+        self.text.splice(&edits)
+    }
+}
+
+pub struct Room {
+    pub tx:    broadcast::Sender<Frame>,
+    /// Random per room, so sequence numbers from a room that has since been
+    /// closed and reopened are not mistaken for this one's.
+    pub epoch: u64,
+    slug:      String,
+    doc:      Mutex<LiveDoc>,
+    /// Who is connected to this instance, by connection id.
+    roster:   Mutex<HashMap<u64, Participant>>,
+    /// Who is connected through other instances, by connection id, with the
+    /// instance each is on.
+    remote:   Mutex<HashMap<u64, (u64, Participant)>>,
+    /// When each other instance with the room open was last heard from.
+    peers:    Mutex<HashMap<u64, Instant>>,
+    /// Latest carets by connection id, for sockets that join later.
+    cursors:  Mutex<HashMap<u64, Vec<Range>>>,
+    fanout:   Option<Arc<dyn Fanout>>,
+    /// False while the room waits for another instance's copy; sockets hold
+    /// off their handshake until it turns true.
+    ready:    watch::Sender<bool>,
+    replay:   Mutex<Replay>,
+    /// Frames `replay` holds on to.
+    keep:     usize,
+    /// Sockets here following `Feed::Full`; the whole-text form of a change
+    /// is only built while there are any.
+    full:     AtomicUsize,
+    /// Sockets here following `Feed::Ops`, which hold on to character ids.
+    /// Deleted characters are only dropped while there are none.
+    replicas: AtomicUsize,
+    /// Held across a flush so two writes of the same room land in order.
+    flushing: tokio::sync::Mutex<()>,
+    opened_at:  DateTime<Utc>,
+    /// Set when the last socket leaves; the room is kept for a grace period
+    /// after it in case they reconnect.
+    emptied_at: Mutex<Option<Instant>>,
+    peak:       AtomicUsize,
+    /// Frames broadcast since the room opened.
+    relayed:    AtomicU64,
+    /// Times a socket fell further behind than the channel holds, and the
+    /// frames it skipped as a result.
+    lags:       AtomicU64,
+    dropped:    AtomicU64,
+    /// Sockets closed for missing a pong, idling or not taking writes.
+    reaped:     AtomicU64,
+    /// Backed by a stored row, so the row going away means the snippet was
+    /// deleted. False for rooms opened ahead of their first save.
+    persisted:  AtomicBool,
+}
+
+/// What the room registry reports about one open room.
+#[derive(Debug, Serialize)]
+pub struct RoomStats {
+    pub slug:         String,
+    pub opened_at:    DateTime<Utc>,
+    pub viewers:      usize,
+    pub peak_viewers: usize,
+    pub relayed:      u64,
+    /// Sockets resynced after falling behind the channel, and frames skipped.
+    pub lags:         u64,
+    pub dropped:      u64,
+    /// Sockets closed as dead rather than by their client.
+    pub reaped:       u64,
+    pub version:      u64,
+    /// Whether nobody is connected and the room is waiting out its grace period.
+    pub idle:         bool,
+}
+
+impl Room {
+    fn new(s: &AppState, slug: &str, row: Option<&SnippetRow>) -> Self {
+        let (tx, _) = broadcast::channel(s.config.room_capacity.max(1));
+        let mut doc = row
+            .map(|r| LiveDoc {
+                text:     restore(r),
+                language: r.language.clone(),
+                version:  r.version,
+                ..Default::default()
+            })
+            .unwrap_or_default();
+        doc.pending = s.fanout.is_some().then(Vec::new);
+        // Nobody holds this copy's ids yet. With fan-out other instances may,
+        // so the stored ones are kept.
+        if s.fanout.is_none() {
+            doc.text.compact();
+        }
+        Self {
+            tx,
+            epoch:      crdt::new_site(),
+            slug:       slug.to_string(),
+            doc:        Mutex::new(doc),
+            roster:     Default::default(),
+            remote:     Default::default(),
+            peers:      Default::default(),
+            cursors:    Default::default(),
+            fanout:     s.fanout.clone(),
+            ready:      watch::channel(s.fanout.is_none()).0,
+            replay:     Default::default(),
+            keep:       s.config.replay_buffer,
+            full:       Default::default(),
+            replicas:   Default::default(),
+            flushing:   Default::default(),
+            opened_at:  Utc::now(),
+            emptied_at: Default::default(),
+            peak:       Default::default(),
+            relayed:    Default::default(),
+            lags:       Default::default(),
+            dropped:    Default::default(),
+            reaped:     Default::default(),
+            persisted:  AtomicBool::new(row.is_some()),
+        }
+    }
+
+    /// The document as it stands, for a socket joining or starting over.
+    pub fn snapshot(&self, feed: Feed) -> Snapshot {
+        // Changes are numbered with the document locked, so `seq` matches it.
+        let doc = self.doc.lock().unwrap();
+        Snapshot {
+            version:  doc.version,
+            seq:      self.replay.lock().unwrap().seq,
+            content:  doc.text.text(),
+            language: doc.language.clone(),
+            state:    (feed == Feed::Ops).then(|| doc.text.snapshot()),
+        }
+    }
+
+    /// The frames for `feed` broadcast after `since`, with the version and
+    /// number they bring a socket up to. `None` when some of them are no
+    /// longer kept, or `since` is not a number this room has reached.
+    pub fn resume(&self, since: u64, feed: Feed) -> Option<(u64, u64, Vec<Frame>)> {
+        let doc = self.doc.lock().unwrap();
+        let replay = self.replay.lock().unwrap();
+        if since < replay.evicted
+            || since > replay.seq
+            || (feed == Feed::Full && since < replay.changed)
+            || (feed == Feed::Ops && replay.renumbered.is_some_and(|r| since <= r))
+        {
+            return None;
+        }
+        let frames = replay.frames
+            .iter()
+            .filter(|f| f.seq > since && f.feed.is_none_or(|x| x == feed))
+            .cloned()
+            .collect();
+        Some((doc.version, replay.seq, frames))
+    }
+
+    /// Counts a socket that skipped `frames` frames by falling behind.
+    pub fn lagged(&self, frames: u64) {
+        self.lags.fetch_add(1, Ordering::Relaxed);
+        self.dropped.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    /// Counts a socket closed because its client looked dead.
+    pub fn reaped(&self) {
+        self.reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Waits until the room has caught up with other instances' copies.
+    pub async fn ready(&self) {
+        let _ = self.ready.subscribe().wait_for(|r| *r).await;
+    }
+
+    /// Asks other instances for their copy of the room, and goes on from its
+    /// own if none answers within `SYNC_WAIT`.
+    fn sync(self: &Arc<Self>) {
+        if self.fanout.is_none() {
+            return;
+        }
+        self.share(Event::Hello);
+        let room = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(SYNC_WAIT).await;
+            room.doc.lock().unwrap().pending = None;
+            room.ready.send_replace(true);
+        });
+    }
+
+    /// Everyone connected, on any instance, in order of arrival.
+    pub fn roster(&self) -> Vec<Participant> {
+        let mut all = self.local_roster();
+        all.extend(self.remote.lock().unwrap().values().map(|(_, p)| p.clone()));
+        all.sort_by_key(|p| p.joined_at);
+        all
+    }
+
+    fn local_roster(&self) -> Vec<Participant> {
+        let mut all: Vec<Participant> = self.roster.lock().unwrap().values().cloned().collect();
+        all.sort_by_key(|p| p.joined_at);
+        all
+    }
+
+    /// A participant connected to this instance.
+    pub fn participant(&self, conn: u64) -> Option<Participant> {
+        self.roster.lock().unwrap().get(&conn).cloned()
+    }
+
+    pub fn viewers(&self) -> usize {
+        self.roster.lock().unwrap().len() + self.remote.lock().unwrap().len()
+    }
+
+    /// Adds a connection following `feed` to the roster and announces it.
+    pub fn join(&self, conn: u64, feed: Feed, name: Option<String>, color: Option<&str>) -> Participant {
+        match feed {
+            Feed::Full  => self.full.fetch_add(1, Ordering::Relaxed),
+            Feed::Ops   => self.replicas.fetch_add(1, Ordering::Relaxed),
+            Feed::Patch => 0,
+        };
+        let me = Participant {
+            conn,
+            name:      display_name(name, conn),
+            color:     self::color(color, conn),
+            joined_at: Utc::now(),
+        };
+        self.roster.lock().unwrap().insert(conn, me.clone());
+        self.peak.fetch_max(self.viewers(), Ordering::Relaxed);
+        self.send(&WsMsg::Joined { participant: me.clone() });
+        me
+    }
+
+    /// Changes a participant's name and/or colour and announces it.
+    pub fn rename(&self, conn: u64, name: Option<String>, color: Option<&str>) {
+        let renamed = {
+            let mut roster = self.roster.lock().unwrap();
+            let Some(p) = roster.get_mut(&conn) else { return };
+            if name.is_some()  { p.name  = display_name(name, conn); }
+            if color.is_some() { p.color = self::color(color, conn); }
+            p.clone()
+        };
+        self.send(&WsMsg::Renamed { participant: renamed });
+    }
+
+    pub fn presence(&self) -> Vec<Presence> {
+        self.cursors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&conn, ranges)| Presence { conn, ranges: ranges.clone() })
+            .collect()
+    }
+
+    fn local_presence(&self) -> Vec<Presence> {
+        let roster = self.roster.lock().unwrap();
+        self.presence().into_iter().filter(|p| roster.contains_key(&p.conn)).collect()
+    }
+
+    /// Records where a connection's carets are and relays it.
+    pub fn set_cursor(&self, conn: u64, ranges: Vec<Range>) {
+        self.cursors.lock().unwrap().insert(conn, ranges.clone());
+        self.send(&WsMsg::BroadcastCursor { conn, ranges });
+    }
+
+    /// Drops a connection from the roster, forgets its carets and tells the
+    /// others it is gone.
+    pub fn leave(&self, conn: u64, feed: Feed) {
+        match feed {
+            Feed::Full  => self.full.fetch_sub(1, Ordering::Relaxed),
+            Feed::Ops   => self.replicas.fetch_sub(1, Ordering::Relaxed),
+            Feed::Patch => 0,
+        };
+        self.roster.lock().unwrap().remove(&conn);
+        self.cursors.lock().unwrap().remove(&conn);
+        self.send(&WsMsg::Left { conn });
+        self.send_viewers();
+    }
+
+    /// Tells this instance's sockets how many people are in the room. Each
+    /// instance counts for itself, so the count is never fanned out.
+    pub fn send_viewers(&self) {
+        self.send_local(&WsMsg::Viewers { count: self.viewers() });
+    }
+
+    /// Sends to every socket in the room, on this instance and any other.
+    pub fn send(&self, msg: &WsMsg) {
+        self.send_local(msg);
+        self.share(Event::Broadcast { msg: msg.clone() });
+    }
+
+    fn send_local(&self, msg: &WsMsg) {
+        self.relay(&[(None, msg)]);
+    }
+
+    fn share(&self, event: Event) {
+        if let Some(fanout) = &self.fanout {
+            fanout.publish(&self.slug, event);
+        }
+    }
+
+    /// Hands a change made here to the other instances, and keeps it for
+    /// replaying while the room is still catching up with them.
+    fn share_ops(&self, doc: &mut LiveDoc, conn: u64, ops: &[Op]) {
+        if self.fanout.is_none() {
+            return;
+        }
+        if let Some(pending) = doc.pending.as_mut() {
+            pending.extend_from_slice(ops);
+        }
+        self.share(Event::Ops { conn, ops: ops.to_vec(), language: doc.language.clone() });
+    }
+
+    /// Handles an event about this room from instance `from`. Document
+    /// changes and closes go through `receive` instead.
+    fn receive(&self, from: u64, event: Event) {
+        self.peers.lock().unwrap().insert(from, Instant::now());
+        match event {
+            Event::Broadcast { msg } => self.relay_remote(from, msg),
+            // Only a room that has caught up itself has a copy worth sending.
+            Event::Hello if *self.ready.borrow() => self.offer(),
+            Event::State { runs, language, participants, presence } => {
+                self.adopt(from, participants, presence);
+                self.catch_up(runs, language);
+            }
+            Event::Roster { participants, presence } => self.adopt(from, participants, presence),
+            _ => {}
+        }
+    }
+
+    /// Sends this instance's copy of the room, and who is connected to it, to
+    /// the others.
+    fn offer(&self) {
+        let (runs, language) = {
+            let doc = self.doc.lock().unwrap();
+            (doc.text.snapshot(), doc.language.clone())
+        };
+        self.share(Event::State {
+            runs,
+            language,
+            participants: self.local_roster(),
+            presence:     self.local_presence(),
+        });
+    }
+
+    /// Passes a message from another instance on to the sockets here, keeping
+    /// the roster and carets in step with it.
+    fn relay_remote(&self, from: u64, msg: WsMsg) {
+        let moved = match &msg {
+            WsMsg::Joined { participant } | WsMsg::Renamed { participant } => {
+                let mut remote = self.remote.lock().unwrap();
+                remote.insert(participant.conn, (from, participant.clone())).is_none()
+            }
+            WsMsg::Left { conn } => {
+                self.cursors.lock().unwrap().remove(conn);
+                self.remote.lock().unwrap().remove(conn).is_some()
+            }
+            WsMsg::BroadcastCursor { conn, ranges } => {
+                self.cursors.lock().unwrap().insert(*conn, ranges.clone());
+                false
+            }
+            _ => false,
+        };
+        self.send_local(&msg);
+        if moved {
+            self.peak.fetch_max(self.viewers(), Ordering::Relaxed);
+            self.send_viewers();
+        }
+    }
+
+    /// Replaces what is known about who is connected through instance
+    /// `from`, announcing the differences here.
+    fn adopt(&self, from: u64, participants: Vec<Participant>, presence: Vec<Presence>) {
+        let (joined, left) = {
+            let mut remote = self.remote.lock().unwrap();
+            let left: Vec<u64> = remote
+                .iter()
+                .filter(|(conn, (at, _))| *at == from && !participants.iter().any(|p| p.conn == **conn))
+                .map(|(&conn, _)| conn)
+                .collect();
+            for conn in &left {
+                remote.remove(conn);
+            }
+            let joined: Vec<Participant> = participants
+                .into_iter()
+                .filter(|p| remote.insert(p.conn, (from, p.clone())).is_none())
+                .collect();
+            (joined, left)
+        };
+        {
+            let mut cursors = self.cursors.lock().unwrap();
+            for conn in &left {
+                cursors.remove(conn);
+            }
+            for p in presence {
+                cursors.insert(p.conn, p.ranges);
+            }
+        }
+        if joined.is_empty() && left.is_empty() {
+            return;
+        }
+        for participant in joined {
+            self.send_local(&WsMsg::Joined { participant });
+        }
+        for conn in left {
+            self.send_local(&WsMsg::Left { conn });
+        }
+        self.peak.fetch_max(self.viewers(), Ordering::Relaxed);
+        self.send_viewers();
+    }
+
+    /// Takes over another instance's document if the room is still waiting
+    /// for one, replaying the changes made here in the meantime.
+    fn catch_up(&self, runs: Vec<Run>, language: String) {
+        {
+            let mut doc = self.doc.lock().unwrap();
+            let Some(pending) = doc.pending.take() else { return };
+            if let Some(mut text) = crdt::Doc::from_snapshot(runs) {
+                text.apply_all(pending);
+                doc.text     = text;
+                doc.language = language;
+                // Position-based history no longer matches the text.
+                doc.history.clear();
+                doc.version += 1;
+            }
+        }
+        self.ready.send_replace(true);
+    }
+
+    /// Numbers a broadcast and sends it in each of its forms, to every socket
+    /// or only those following a feed. The frames are kept for replaying,
+    /// except whole-text ones, and the numbering lock is held while sending so
+    /// the channel sees them in order.
+    fn relay(&self, forms: &[(Option<Feed>, &WsMsg)]) {
+        let mut replay = self.replay.lock().unwrap();
+        replay.seq += 1;
+        let seq = replay.seq;
+        if forms.iter().any(|(feed, _)| feed.is_some()) {
+            replay.changed = seq;
+        }
+        for &(feed, msg) in forms {
+            let mut value = serde_json::to_value(msg).unwrap();
+            value["seq"] = seq.into();
+            let frame = Frame { feed, seq, text: value.to_string(), last: matches!(msg, WsMsg::Expired) };
+            if feed != Some(Feed::Full) {
+                replay.frames.push_back(frame.clone());
+            }
+            self.relayed.fetch_add(1, Ordering::Relaxed);
+            let _ = self.tx.send(frame);
+        }
+        while replay.frames.len() > self.keep {
+            if let Some(old) = replay.frames.pop_front() {
+                replay.evicted = old.seq;
+            }
+        }
+    }
+
+    fn stats(&self, slug: &str) -> RoomStats {
+        RoomStats {
+            slug:         slug.to_string(),
+            opened_at:    self.opened_at,
+            viewers:      self.viewers(),
+            peak_viewers: self.peak.load(Ordering::Relaxed),
+            relayed:      self.relayed.load(Ordering::Relaxed),
+            lags:         self.lags.load(Ordering::Relaxed),
+            dropped:      self.dropped.load(Ordering::Relaxed),
+            reaped:       self.reaped.load(Ordering::Relaxed),
+            version:      self.doc.lock().unwrap().version,
+            idle:         self.emptied_at.lock().unwrap().is_some(),
+        }
+    }
+
+    /// Numbers a change and relays it to each feed in its own form. Called with
+    /// the document locked, so changes go out in the order they were applied.
+    fn publish(&self, doc: &mut LiveDoc, conn: u64, applied: Applied, history: usize) {
+        doc.version += 1;
+        doc.history.push_back(applied.edits.clone());
+        while doc.history.len() > history {
+            doc.history.pop_front();
+        }
+        let ops   = (!applied.ops.is_empty()).then_some(WsMsg::BroadcastOps { ops: applied.ops });
+        let patch = WsMsg::BroadcastPatch {
+            version:  doc.version,
+            conn,
+            ops:      applied.edits,
+            language: doc.language.clone(),
+        };
+        // The whole text is costly to build for every keystroke; skip it when
+        // nobody here reads it.
+        let full  = (self.full.load(Ordering::Relaxed) > 0)
+            .then(|| WsMsg::BroadcastEdit { content: doc.text.text(), language: doc.language.clone() });
+        let mut forms = vec![(Some(Feed::Patch), &patch)];
+        if let Some(ops) = &ops {
+            forms.insert(0, (Some(Feed::Ops), ops));
+        }
+        if let Some(full) = &full {
+            forms.push((Some(Feed::Full), full));
+        }
+        self.relay(&forms);
+    }
+}
+
+/// Joins the live room for `slug`, opening it from `row` if nobody is in it
+/// yet, or picking it back up during its grace period. Subscribes while the
+/// registry entry is held, so the room cannot be retired in between.
+pub fn get_or_create_room(
+    s: &AppState,
+    slug: &str,
+    row: Option<&SnippetRow>,
+) -> (Arc<Room>, broadcast::Receiver<Frame>) {
+    let mut opened = false;
+    let room = s.rooms
+        .entry(slug.to_string())
+        .or_insert_with(|| {
+            opened = true;
+            Arc::new(Room::new(s, slug, row))
+        });
+    let rx = room.tx.subscribe();
+    *room.emptied_at.lock().unwrap() = None;
+    let room = room.clone();
+    if opened {
+        room.sync();
+    }
+    (room, rx)
+}
+
+/// A room kept open for an edit made over REST. See `hold`.
+pub struct Hold {
+    room: Arc<Room>,
+    _rx:  broadcast::Receiver<Frame>,
+}
+
+/// With fan-out, another instance may have the room open even when this one
+/// does not, and would write its text back over a store-only edit. So the
+/// room is opened here too, caught up, and edited like any other. `None` on a
+/// single instance, where the store is edited directly when no room is open.
+pub async fn hold(s: &AppState, slug: &str) -> Result<Option<Hold>, AppError> {
+    if s.fanout.is_none() {
+        return Ok(None);
+    }
+    // Burn-after-reading rows never get a room, so their edits never reach
+    // the revision history through a flush.
+    let Some(row) = s.store.get(slug).await?.filter(|r| !r.is_expired() && !r.burn_after_reading) else {
+        return Ok(None);
+    };
+    let (room, rx) = get_or_create_room(s, slug, Some(&row));
+    room.ready().await;
+    Ok(Some(Hold { room, _rx: rx }))
+}
+
+/// Lets go of a held room, releasing it if nobody else is in it.
+pub async fn unhold(s: &Arc<AppState>, slug: &str, hold: Option<Hold>) {
+    let Some(Hold { room, _rx }) = hold else { return };
+    drop(_rx);
+    if room.tx.receiver_count() == 0 {
+        release(s, slug, &room).await;
+    }
+}
+
+/// Sends a final message to everyone in the room, on every instance, and
+/// forgets it, dropping any unflushed edits. Connected sockets close
+/// themselves after forwarding `WsMsg::Expired`.
+pub fn close_room(s: &AppState, slug: &str, last: &WsMsg) {
+    close_local(&s.rooms, slug, last);
+    if let Some(fanout) = &s.fanout {
+        fanout.publish(slug, Event::Close { msg: last.clone() });
+    }
+}
+
+fn close_local(rooms: &Rooms, slug: &str, last: &WsMsg) {
+    if let Some((_, room)) = rooms.remove(slug) {
+        room.send_local(last);
+        closed(slug, &room);
+    }
+}
+
+/// Sends a message to everyone in the slug's room, whichever instance has it
+/// open.
+pub fn broadcast(s: &AppState, slug: &str, msg: WsMsg) {
+    if let Some(room) = s.rooms.get(slug) {
+        room.send_local(&msg);
+    }
+    if let Some(fanout) = &s.fanout {
+        fanout.publish(slug, Event::Broadcast { msg });
+    }
+}
+
+/// Applies an envelope from another instance to this one's copy of its room.
+pub fn receive(s: &Arc<AppState>, envelope: Envelope) {
+    let Envelope { from, slug, event } = envelope;
+    match event {
+        Event::Ops { conn, ops, language } => apply_remote(s, &slug, conn, ops, language),
+        Event::Close { msg } => close_local(&s.rooms, &slug, &msg),
+        // A room that has caught up already takes in what the other copy has
+        // beyond its own instead, as after the instances lost touch.
+        Event::State { runs, participants, presence, .. } if s.rooms.get(&slug).is_some_and(|r| *r.ready.borrow()) => {
+            if let Some(room) = s.rooms.get(&slug).map(|r| r.clone()) {
+                room.receive(from, Event::Roster { participants, presence });
+            }
+            merge(s, &slug, runs);
+        }
+        event => {
+            let room = s.rooms.get(&slug).map(|r| r.clone());
+            if let Some(room) = room {
+                room.receive(from, event);
+            }
+        }
+    }
+}
+
+/// Folds another instance's copy of a room into this one's. Only relayed
+/// here: the other instance merges this copy in turn.
+fn merge(s: &Arc<AppState>, slug: &str, runs: Vec<Run>) {
+    let Some(theirs) = crdt::Doc::from_snapshot(runs) else { return };
+    change(s, slug, crdt::SERVER_SITE, false, |doc| {
+        let applied = doc.text.merge(&theirs);
+        (!applied.ops.is_empty()).then_some(applied)
+    });
+}
+
+/// Called when events between the instances may have been lost, e.g. while
+/// Redis was unreachable: every open room sends its copy to the others and
+/// asks for theirs, so the copies that went on alone merge again.
+pub fn reconcile(s: &AppState) {
+    let open: Vec<Arc<Room>> = s.rooms.iter().map(|r| r.value().clone()).collect();
+    for room in open.iter().filter(|r| *r.ready.borrow()) {
+        room.share(Event::Hello);
+        room.offer();
+    }
+}
+
+/// Tells the other instances who is connected here, and takes out of each
+/// room the instances that have gone quiet.
+pub fn heartbeat(s: &AppState) {
+    let timeout = s.config.fanout_heartbeat * 3;
+    let open: Vec<Arc<Room>> = s.rooms.iter().map(|r| r.value().clone()).collect();
+    for room in open {
+        room.share(Event::Roster { participants: room.local_roster(), presence: room.local_presence() });
+        let mut silent = Vec::new();
+        room.peers.lock().unwrap().retain(|&from, heard| {
+            let alive = heard.elapsed() < timeout;
+            if !alive { silent.push(from); }
+            alive
+        });
+        for from in silent {
+            room.adopt(from, Vec::new(), Vec::new());
+        }
+    }
+}
+
+fn closed(slug: &str, room: &Room) {
+    let s = room.stats(slug);
+    let open = (Utc::now() - s.opened_at).num_seconds();
+    info!("closed room /{slug} after {open}s: peak {} viewers, {} frames relayed", s.peak_viewers, s.relayed);
+}
+
+/// Every open room, busiest first.
+pub fn stats(rooms: &Rooms) -> Vec<RoomStats> {
+    let mut all: Vec<RoomStats> = rooms.iter().map(|r| r.stats(r.key())).collect();
+    all.sort_by(|a, b| b.viewers.cmp(&a.viewers).then_with(|| a.slug.cmp(&b.slug)));
+    all
+}
+
+/// Closes rooms whose snippet has expired or been deleted behind this
+/// process's back, e.g. by Mongo's TTL index or another instance.
+pub async fn evict_stale(s: &AppState) {
+    let persisted: Vec<String> = s.rooms
+        .iter()
+        .filter(|r| r.persisted.load(Ordering::Relaxed))
+        .map(|r| r.key().clone())
+        .collect();
+    for slug in persisted {
+        match s.store.get(&slug).await {
+            Ok(Some(row)) if !row.is_expired() => {}
+            Ok(_)  => close_room(s, &slug, &WsMsg::Expired),
+            Err(e) => warn!("checking room /{slug} failed: {e:?}"),
+        }
+    }
+}
+
+/// Picks up the persisted CRDT state, unless the text was changed without it.
+fn restore(row: &SnippetRow) -> crdt::Doc {
+    row.crdt
+        .as_deref()
+        .and_then(crdt::Doc::decode)
+        .filter(|doc| doc.text() == row.content)
+        .unwrap_or_else(|| crdt::Doc::from_text(&row.content))
+}
+
+/// Replaces a stored row's text with the live room's, which may be ahead.
+pub fn overlay(rooms: &Rooms, row: &mut SnippetRow) {
+    if let Some(room) = rooms.get(&row.slug) {
+        let doc = room.doc.lock().unwrap();
+        row.content  = doc.text.text();
+        row.language.clone_from(&doc.language);
+        row.version  = row.version.max(doc.version);
+    }
+}
+
+/// The live room's version, if one is open.
+pub fn version(rooms: &Rooms, slug: &str) -> Option<u64> {
+    rooms.get(slug).map(|room| room.doc.lock().unwrap().version)
+}
+
+/// Seeds an already-open room with a freshly created row's text. Clients may
+/// connect before the first save, and that save is already in the store.
+pub fn reset(s: &AppState, slug: &str, content: &str, language: &str) {
+    if let Some(room) = s.rooms.get(slug) {
+        room.persisted.store(true, Ordering::Relaxed);
+        let mut doc = room.doc.lock().unwrap();
+        let applied = doc.text.replace(content);
+        doc.language = language.to_string();
+        doc.dirty    = false;
+        if !applied.ops.is_empty() {
+            room.share_ops(&mut doc, crdt::SERVER_SITE, &applied.ops);
+            room.publish(&mut doc, crdt::SERVER_SITE, applied, s.config.patch_history);
+        }
+    }
+}
+
+/// Applies a full-content edit to the live room and relays it to everyone in
+/// it. `conn` is the editing socket, or `crdt::SERVER_SITE` for the server.
+/// Returns false when no room is open, in which case the caller writes to the
+/// store.
+pub fn edit(s: &Arc<AppState>, slug: &str, conn: u64, content: String, language: String) -> bool {
+    matches!(edit_if(s, slug, conn, content, language, None), Ok(Some(_)))
+}
+
+/// As `edit`, but only while the room is still at version `expected`, if
+/// given. Returns the room's version afterwards, `None` when no room is open,
+/// or `Err` with the version the room is really at.
+pub fn edit_if(
+    s: &Arc<AppState>,
+    slug: &str,
+    conn: u64,
+    content: String,
+    language: String,
+    expected: Option<u64>,
+) -> Result<Option<u64>, u64> {
+    let mut stale = None;
+    let version = change(s, slug, conn, true, |doc| {
+        if expected.is_some_and(|v| v != doc.version) {
+            stale = Some(doc.version);
+            return None;
+        }
+        let applied = doc.text.replace(&content);
+        let relabelled = doc.language != language;
+        doc.language = language;
+        (!applied.ops.is_empty() || relabelled).then_some(applied)
+    });
+    stale.map_or(Ok(version), Err)
+}
+
+/// Applies CRDT operations from a client. Ones that refer to characters the
+/// room has never seen, or insert under ids the client may not use, are
+/// dropped; the rest are relayed.
+pub fn apply_ops(s: &Arc<AppState>, slug: &str, conn: u64, mut ops: Vec<Op>) {
+    ops.retain(|op| op.allowed_from(conn));
+    change(s, slug, conn, true, |doc| {
+        let applied = doc.text.apply_all(ops);
+        (!applied.ops.is_empty()).then_some(applied)
+    });
+}
+
+/// Applies a change from another instance's copy of the room, where it was
+/// already relayed and shared.
+fn apply_remote(s: &Arc<AppState>, slug: &str, conn: u64, ops: Vec<Op>, language: String) {
+    change(s, slug, conn, false, |doc| {
+        let applied = doc.text.apply_all(ops);
+        let relabelled = doc.language != language;
+        doc.language = language;
+        (!applied.ops.is_empty() || relabelled).then_some(applied)
+    });
+}
+
+/// Applies position-based edits made against version `base`, rebasing them
+/// over anything that landed since. Refused when `base` is unknown or too far
+/// back, or the edits do not fit the text.
+pub fn apply_patch(s: &Arc<AppState>, slug: &str, conn: u64, base: u64, edits: Vec<TextOp>) -> Result<(), Rejection> {
+    let mut rejected = None;
+    change(s, slug, conn, true, |doc| {
+        let applied = doc.rebase(base, &edits);
+        if applied.is_none() {
+            rejected = Some(Rejection { version: doc.version, content: doc.text.text() });
+        }
+        applied.filter(|a| !a.ops.is_empty())
+    });
+    rejected.map_or(Ok(()), Err)
+}
+
+/// Runs `f` against the live document and, if it reports a change, publishes
+/// it, shares it with other instances if `share`, and schedules a flush.
+/// Returns the room's version afterwards, or `None` when no room is open.
+fn change(
+    s: &Arc<AppState>,
+    slug: &str,
+    conn: u64,
+    share: bool,
+    f: impl FnOnce(&mut LiveDoc) -> Option<Applied>,
+) -> Option<u64> {
+    let (room, version) = {
+        // Holding the registry entry keeps `retire_if_idle` from dropping the
+        // room between marking it dirty and scheduling the flush.
+        let room = s.rooms.get(slug)?;
+        let mut doc = room.doc.lock().unwrap();
+        let Some(applied) = f(&mut doc) else { return Some(doc.version) };
+        doc.dirty = true;
+        let schedule = !std::mem::replace(&mut doc.flush_pending, true);
+        if share {
+            room.share_ops(&mut doc, conn, &applied.ops);
+        }
+        room.publish(&mut doc, conn, applied, s.config.patch_history);
+        let version = doc.version;
+        drop(doc);
+        if !schedule {
+            return Some(version);
+        }
+        (room.clone(), version)
+    };
+
+    let (s, slug) = (s.clone(), slug.to_string());
+    tokio::spawn(async move {
+        tokio::time::sleep(s.config.write_behind).await;
+        room.doc.lock().unwrap().flush_pending = false;
+        // A room closed in the meantime was deleted or expired; its edits go with it.
+        if is_current(&s.rooms, &slug, &room) {
+            flush(&s, &slug, &room).await;
+            retire_if_idle(&s.rooms, &slug, &room, s.config.room_grace);
+        }
+    });
+    Some(version)
+}
+
+/// Writes the live text and its CRDT state to the store if they changed, and
+/// records a revision.
+pub async fn flush(s: &AppState, slug: &str, room: &Room) {
+    let _order = room.flushing.lock().await;
+    let (content, language, state, version) = {
+        let mut doc = room.doc.lock().unwrap();
+        if !doc.dirty {
+            return;
+        }
+        doc.dirty = false;
+        // Tombstones would otherwise grow the stored state with every edit.
+        // A socket joining as a replica is counted before it reads the
+        // document, so it gets the new ids either way.
+        if room.fanout.is_none() && room.replicas.load(Ordering::Relaxed) == 0 && doc.text.compact() {
+            let mut replay = room.replay.lock().unwrap();
+            replay.renumbered = Some(replay.seq);
+        }
+        (doc.text.text(), doc.language.clone(), doc.text.encode(), doc.version)
+    };
+
+    let patch = SnippetPatch {
+        content:  Some(content.clone()),
+        language: Some(language.clone()),
+        crdt:     Some(state),
+        version:  Some(version),
+        ..Default::default()
+    };
+    if let Err(e) = s.store.patch(slug, patch).await {
+        warn!("failed to flush /{slug}: {e:?}");
+        room.doc.lock().unwrap().dirty = true;
+        return;
+    }
+    revisions::record(s, slug, &content, &language).await;
+}
+
+/// Called when the last socket leaves: persists the room, and closes it once
+/// the grace period passes with nobody back.
+pub async fn release(s: &Arc<AppState>, slug: &str, room: &Arc<Room>) {
+    *room.emptied_at.lock().unwrap() = Some(Instant::now());
+    // As with the write-behind timer, a closed room's edits are not written back.
+    if !is_current(&s.rooms, slug, room) {
+        return;
+    }
+    flush(s, slug, room).await;
+
+    let (s, slug, room) = (s.clone(), slug.to_string(), room.clone());
+    tokio::spawn(async move {
+        tokio::time::sleep(s.config.room_grace).await;
+        retire_if_idle(&s.rooms, &slug, &room, s.config.room_grace);
+    });
+}
+
+/// Persists every open room. Run once the server has stopped taking requests.
+pub async fn flush_all(s: &AppState) {
+    let open: Vec<(String, Arc<Room>)> = s.rooms
+        .iter()
+        .map(|r| (r.key().clone(), r.value().clone()))
+        .collect();
+    for (slug, room) in open {
+        flush(s, &slug, &room).await;
+    }
+}
+
+fn is_current(rooms: &Rooms, slug: &str, room: &Arc<Room>) -> bool {
+    rooms.get(slug).is_some_and(|r| Arc::ptr_eq(&r, room))
+}
+
+/// Drops a room nobody has been connected to for `grace`, unless it still has
+/// edits to write.
+fn retire_if_idle(rooms: &Rooms, slug: &str, room: &Arc<Room>, grace: Duration) {
+    let retired = rooms.remove_if(slug, |_, r| {
+        Arc::ptr_eq(r, room)
+            && r.tx.receiver_count() == 0
+            && r.emptied_at.lock().unwrap().is_some_and(|t| t.elapsed() >= grace)
+            && !r.doc.lock().unwrap().dirty
+    });
+    if let Some((_, room)) = retired {
+        closed(slug, &room);
+    }
+}
@@ -0,0 +1,230 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::collections::VecDeque;
+
+use super::{SnippetPatch, SnippetStore};
+use crate::chat::ChatMessage;
+use crate::error::AppError;
+use crate::revisions::Revision;
+use crate::{FileData, ImageData, SnippetRow};
+
+/// Process-local store for development and tests. Everything is lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    rows:      DashMap<String, SnippetRow>,
+    revisions: DashMap<String, Vec<Revision>>,
+    chat:      DashMap<String, VecDeque<ChatMessage>>,
+    /// Uploaded blob URLs and the snippet each belongs to, once attached.
+    blobs:     DashMap<String, Option<String>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SnippetStore for MemoryStore {
+    async fn exists(&self, slug: &str) -> Result<bool, AppError> {
+        Ok(self.rows.contains_key(slug))
+    }
+
+    async fn create(&self, row: SnippetRow) -> Result<(), AppError> {
+        match self.rows.entry(row.slug.clone()) {
+            Entry::Occupied(_) => Err(AppError::Conflict(format!("'{}' already exists", row.slug))),
+            Entry::Vacant(v) => {
+                v.insert(row);
+                Ok(())
+            }
+        }
+    }
+
+    async fn get(&self, slug: &str) -> Result<Option<SnippetRow>, AppError> {
+        Ok(self.rows.get(slug).map(|r| r.clone()))
+    }
+
+    async fn record_view(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError> {
+        let Some(mut row) = self.rows.get_mut(slug) else { return Ok(None) };
+        if row.expires_at <= now || row.max_views.is_some_and(|max| row.views >= max) {
+            return Ok(None);
+        }
+        row.views += 1;
+        if row.max_views.is_some_and(|max| row.views >= max) {
+            row.expires_at = row.expires_at.min(now);
+        }
+        Ok(Some(row.clone()))
+    }
+
+    async fn patch(&self, slug: &str, patch: SnippetPatch) -> Result<Option<u64>, AppError> {
+        let Some(mut row) = self.rows.get_mut(slug) else { return Ok(None) };
+        if patch.if_version.is_some_and(|v| v != row.version) {
+            return Ok(None);
+        }
+        match patch.version {
+            Some(v) => row.version = row.version.max(v),
+            None if patch.touches_text() => row.version += 1,
+            None => {}
+        }
+        if let Some(c) = patch.content    { row.content    = c; }
+        if let Some(l) = patch.language   { row.language   = l; }
+        if let Some(i) = patch.images     { row.images     = i; }
+        if let Some(e) = patch.expires_at { row.expires_at = e; }
+        if let Some(c) = patch.crdt       { row.crdt       = Some(c); }
+        Ok(Some(row.version))
+    }
+
+    async fn delete(&self, slug: &str) -> Result<Option<SnippetRow>, AppError> {
+        self.revisions.remove(slug);
+        self.chat.remove(slug);
+        Ok(self.rows.remove(slug).map(|(_, row)| row))
+    }
+
+    // The row's shard lock is held throughout, so each of these is atomic.
+
+    async fn add_image(&self, slug: &str, image: ImageData) -> Result<bool, AppError> {
+        let Some(mut row) = self.rows.get_mut(slug) else { return Ok(false) };
+        if row.images.iter().any(|i| i.id == image.id) {
+            return Ok(false);
+        }
+        row.images.push(image);
+        Ok(true)
+    }
+
+    async fn remove_image(&self, slug: &str, id: &str) -> Result<bool, AppError> {
+        let Some(mut row) = self.rows.get_mut(slug) else { return Ok(false) };
+        let before = row.images.len();
+        row.images.retain(|i| i.id != id);
+        Ok(row.images.len() < before)
+    }
+
+    async fn add_file(&self, slug: &str, file: FileData) -> Result<bool, AppError> {
+        let Some(mut row) = self.rows.get_mut(slug) else { return Ok(false) };
+        if row.files.iter().any(|f| f.id == file.id) {
+            return Ok(false);
+        }
+        row.files.push(file);
+        Ok(true)
+    }
+
+    async fn remove_file(&self, slug: &str, id: &str) -> Result<bool, AppError> {
+        let Some(mut row) = self.rows.get_mut(slug) else { return Ok(false) };
+        let before = row.files.len();
+        row.files.retain(|f| f.id != id);
+        Ok(row.files.len() < before)
+    }
+
+    async fn expired(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<SnippetRow>, AppError> {
+        let mut rows: Vec<SnippetRow> = self.rows
+            .iter()
+            .filter(|r| r.expires_at <= now)
+            .map(|r| r.clone())
+            .collect();
+        rows.sort_by_key(|r| r.expires_at);
+        rows.truncate(limit);
+        Ok(rows)
+    }
+
+    async fn delete_if_expired(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError> {
+        let row = self.rows.remove_if(slug, |_, r| r.expires_at <= now).map(|(_, row)| row);
+        if row.is_some() {
+            self.revisions.remove(slug);
+            self.chat.remove(slug);
+        }
+        Ok(row)
+    }
+
+    async fn record_revision(
+        &self,
+        slug: &str,
+        content: &str,
+        language: &str,
+        coalesce: chrono::Duration,
+    ) -> Result<Option<u64>, AppError> {
+        if self.rows.get(slug).is_none_or(|r| r.burn_after_reading) {
+            return Ok(None);
+        }
+        let now = Utc::now();
+        let mut revs = self.revisions.entry(slug.to_string()).or_default();
+        if let Some(last) = revs.last_mut() {
+            if last.content == content && last.language == language {
+                return Ok(Some(last.rev));
+            }
+            if now - last.created_at < coalesce {
+                last.content    = content.to_string();
+                last.language   = language.to_string();
+                last.updated_at = now;
+                return Ok(Some(last.rev));
+            }
+        }
+        let rev = revs.last().map_or(1, |r| r.rev + 1);
+        revs.push(Revision {
+            slug:       slug.to_string(),
+            rev,
+            content:    content.to_string(),
+            language:   language.to_string(),
+            created_at: now,
+            updated_at: now,
+        });
+        Ok(Some(rev))
+    }
+
+    async fn list_revisions(&self, slug: &str) -> Result<Vec<Revision>, AppError> {
+        Ok(self.revisions
+            .get(slug)
+            .map(|revs| revs.iter().rev().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn get_revision(&self, slug: &str, rev: u64) -> Result<Option<Revision>, AppError> {
+        Ok(self.revisions
+            .get(slug)
+            .and_then(|revs| revs.iter().find(|r| r.rev == rev).cloned()))
+    }
+
+    async fn add_chat(&self, msg: &ChatMessage, keep: usize) -> Result<(), AppError> {
+        if !self.rows.contains_key(&msg.slug) {
+            return Ok(());
+        }
+        let mut log = self.chat.entry(msg.slug.clone()).or_default();
+        log.push_back(msg.clone());
+        while log.len() > keep {
+            log.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn list_chat(&self, slug: &str, limit: usize) -> Result<Vec<ChatMessage>, AppError> {
+        Ok(self.chat
+            .get(slug)
+            .map(|log| log.iter().skip(log.len().saturating_sub(limit)).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn issue_blob(&self, url: &str) -> Result<(), AppError> {
+        self.blobs.entry(url.to_string()).or_insert(None);
+        Ok(())
+    }
+
+    async fn claim_blob(&self, url: &str, slug: &str) -> Result<bool, AppError> {
+        let Some(mut owner) = self.blobs.get_mut(url) else { return Ok(true) };
+        match owner.as_deref() {
+            Some(o) => Ok(o == slug),
+            None    => {
+                *owner = Some(slug.to_string());
+                Ok(true)
+            }
+        }
+    }
+
+    async fn release_blobs(&self, slug: &str) -> Result<Vec<String>, AppError> {
+        let mut urls = Vec::new();
+        self.blobs.retain(|url, owner| {
+            let owned = owner.as_deref() == Some(slug);
+            if owned { urls.push(url.clone()); }
+            !owned
+        });
+        Ok(urls)
+    }
+}
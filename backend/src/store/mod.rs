@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::chat::ChatMessage;
+use crate::error::AppError;
+use crate::revisions::Revision;
+use crate::{FileData, ImageData, SnippetRow};
+
+pub mod memory;
+pub mod mongo;
+pub mod postgres;
+
+pub use memory::MemoryStore;
+pub use mongo::MongoStore;
+pub use postgres::PostgresStore;
+
+/// Fields a PATCH may change. `None` leaves the stored value untouched.
+#[derive(Debug, Default)]
+pub struct SnippetPatch {
+    pub content:    Option<String>,
+    pub language:   Option<String>,
+    pub images:     Option<Vec<ImageData>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Encoded `crdt::Doc`; written together with `content` by room flushes.
+    pub crdt:       Option<Vec<u8>>,
+    /// The live room's version, passed by room flushes. The stored version
+    /// never goes backwards. Without it, a patch that sets `content` or
+    /// `language` bumps the stored version by one.
+    pub version:    Option<u64>,
+    /// Apply only if the stored version is exactly this.
+    pub if_version: Option<u64>,
+}
+
+impl SnippetPatch {
+    fn touches_text(&self) -> bool {
+        self.content.is_some() || self.language.is_some()
+    }
+}
+
+/// Persistence for snippets. Handlers only ever talk to this trait, so the
+/// backing database is picked once at startup (see `db::connect`).
+#[async_trait]
+pub trait SnippetStore: Send + Sync {
+    async fn exists(&self, slug: &str) -> Result<bool, AppError>;
+
+    /// Inserts a new row. Fails with `AppError::Conflict` if the slug is taken.
+    async fn create(&self, row: SnippetRow) -> Result<(), AppError>;
+
+    async fn get(&self, slug: &str) -> Result<Option<SnippetRow>, AppError>;
+
+    /// Atomically counts one view of a live row and returns it as it is
+    /// afterwards. The view that uses up `max_views` also sets `expires_at` to
+    /// `now`, so the room closes and the reaper collects it. `None` if the row
+    /// is missing, expired or already out of views.
+    async fn record_view(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError>;
+
+    /// Applies the patch in one atomic write and returns the version the row
+    /// is at afterwards. `None` if the row is missing or `if_version` did not
+    /// match, in which case nothing changed.
+    async fn patch(&self, slug: &str, patch: SnippetPatch) -> Result<Option<u64>, AppError>;
+
+    /// Removes the row and hands back what was deleted, if anything.
+    async fn delete(&self, slug: &str) -> Result<Option<SnippetRow>, AppError>;
+
+    /// Attaches an image in a single atomic write, so concurrent attaches all
+    /// land. `false` if the snippet is missing or already has an image with
+    /// that id.
+    async fn add_image(&self, slug: &str, image: ImageData) -> Result<bool, AppError>;
+
+    /// Detaches an image atomically. `false` if it was not attached.
+    async fn remove_image(&self, slug: &str, id: &str) -> Result<bool, AppError>;
+
+    /// As `add_image`, for files.
+    async fn add_file(&self, slug: &str, file: FileData) -> Result<bool, AppError>;
+
+    async fn remove_file(&self, slug: &str, id: &str) -> Result<bool, AppError>;
+
+    /// Up to `limit` rows whose `expires_at` is at or before `now`, oldest first.
+    async fn expired(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<SnippetRow>, AppError>;
+
+    /// Deletes the row only if it is still expired at `now`, so a slug that was
+    /// re-created in the meantime survives.
+    async fn delete_if_expired(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError>;
+
+    /// Saves `content`/`language` as the newest revision, folding it into the
+    /// latest one if that started less than `coalesce` ago. Content identical
+    /// to the latest revision records nothing. Returns the revision now holding
+    /// it, or `None` if there is no such snippet or it is burn-after-reading,
+    /// whose text must never reach the history.
+    async fn record_revision(
+        &self,
+        slug: &str,
+        content: &str,
+        language: &str,
+        coalesce: chrono::Duration,
+    ) -> Result<Option<u64>, AppError>;
+
+    /// All revisions of a snippet, newest first.
+    async fn list_revisions(&self, slug: &str) -> Result<Vec<Revision>, AppError>;
+
+    async fn get_revision(&self, slug: &str, rev: u64) -> Result<Option<Revision>, AppError>;
+
+    /// Appends a chat message to its slug's history, then drops all but the
+    /// newest `keep`. Does nothing if there is no such snippet.
+    async fn add_chat(&self, msg: &ChatMessage, keep: usize) -> Result<(), AppError>;
+
+    /// Up to `limit` of a slug's latest chat messages, oldest first.
+    async fn list_chat(&self, slug: &str, limit: usize) -> Result<Vec<ChatMessage>, AppError>;
+
+    /// Records the URL of a fresh upload, owned by no snippet yet.
+    async fn issue_blob(&self, url: &str) -> Result<(), AppError>;
+
+    /// Makes `slug` the owner of a blob issued here, atomically. `false` only
+    /// when another snippet already owns it; URLs never issued here, such as
+    /// data URLs or older Cloudinary links, pass without becoming anyone's.
+    async fn claim_blob(&self, url: &str, slug: &str) -> Result<bool, AppError>;
+
+    /// Forgets every blob `slug` owns and returns their URLs, for deleting.
+    async fn release_blobs(&self, slug: &str) -> Result<Vec<String>, AppError>;
+}
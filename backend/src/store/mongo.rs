@@ -0,0 +1,408 @@
+use async_trait::async_trait;
+use bson::{doc, to_bson, Document};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use mongodb::{
+    error::{ErrorKind, WriteFailure},
+    options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{SnippetPatch, SnippetStore};
+use crate::chat::ChatMessage;
+use crate::error::AppError;
+use crate::revisions::Revision;
+use crate::{FileData, ImageData, SnippetRow};
+
+/// An uploaded blob in the `blobs` collection, keyed by its URL. `slug` is
+/// set once a snippet claims it.
+#[derive(Serialize, Deserialize)]
+struct BlobDoc {
+    #[serde(rename = "_id")]
+    url:       String,
+    slug:      Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    issued_at: DateTime<Utc>,
+}
+
+/// `Revision` as stored in the `revisions` collection, with BSON dates.
+#[derive(Serialize, Deserialize)]
+struct RevisionDoc {
+    slug:       String,
+    rev:        i64,
+    content:    String,
+    language:   String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    created_at: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    updated_at: DateTime<Utc>,
+}
+
+/// `ChatMessage` as stored in the `chat` collection, with a BSON date.
+#[derive(Serialize, Deserialize)]
+struct ChatDoc {
+    /// Per-slug number from the snippet's `chat_seq` counter. Unlike `sent_at`
+    /// it never ties, so trimming and ordering go by it.
+    #[serde(default)]
+    seq:     i64,
+    id:      String,
+    slug:    String,
+    conn:    i64,
+    name:    String,
+    color:   String,
+    text:    String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    sent_at: DateTime<Utc>,
+}
+
+impl From<ChatDoc> for ChatMessage {
+    fn from(d: ChatDoc) -> Self {
+        ChatMessage {
+            id:      d.id,
+            slug:    d.slug,
+            conn:    d.conn as u64,
+            name:    d.name,
+            color:   d.color,
+            text:    d.text,
+            sent_at: d.sent_at,
+        }
+    }
+}
+
+impl From<RevisionDoc> for Revision {
+    fn from(d: RevisionDoc) -> Self {
+        Revision {
+            slug:       d.slug,
+            rev:        d.rev as u64,
+            content:    d.content,
+            language:   d.language,
+            created_at: d.created_at,
+            updated_at: d.updated_at,
+        }
+    }
+}
+
+pub struct MongoStore {
+    db: Database,
+}
+
+impl MongoStore {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn col(&self) -> Collection<SnippetRow> {
+        self.db.collection::<SnippetRow>("snippets")
+    }
+
+    fn revs(&self) -> Collection<RevisionDoc> {
+        self.db.collection::<RevisionDoc>("revisions")
+    }
+
+    fn chat(&self) -> Collection<ChatDoc> {
+        self.db.collection::<ChatDoc>("chat")
+    }
+
+    fn blobs(&self) -> Collection<BlobDoc> {
+        self.db.collection::<BlobDoc>("blobs")
+    }
+
+    /// Removes everything kept alongside a snippet: its revisions and chat.
+    async fn drop_history(&self, slug: &str) -> Result<(), AppError> {
+        self.revs().delete_many(doc! { "slug": slug }, None).await?;
+        self.chat().delete_many(doc! { "slug": slug }, None).await?;
+        Ok(())
+    }
+
+    /// Appends `item` to the array `field` unless an element there already has
+    /// `id`. The filter and the `$push` are one write, so concurrent appends
+    /// cannot overwrite each other.
+    async fn push(&self, slug: &str, field: &str, id: &str, item: bson::Bson) -> Result<bool, AppError> {
+        let res = self.col()
+            .update_one(
+                doc! { "slug": slug, format!("{field}.id"): { "$ne": id } },
+                doc! { "$push": { field: item } },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    /// Removes the element with `id` from the array `field`.
+    async fn pull(&self, slug: &str, field: &str, id: &str) -> Result<bool, AppError> {
+        let res = self.col()
+            .update_one(doc! { "slug": slug }, doc! { "$pull": { field: { "id": id } } }, None)
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(w)) if w.code == 11000
+    )
+}
+
+#[async_trait]
+impl SnippetStore for MongoStore {
+    async fn exists(&self, slug: &str) -> Result<bool, AppError> {
+        Ok(self.col().count_documents(doc! { "slug": slug }, None).await? > 0)
+    }
+
+    async fn create(&self, row: SnippetRow) -> Result<(), AppError> {
+        let slug = row.slug.clone();
+        match self.col().insert_one(row, None).await {
+            // A previous row under this slug may have been dropped by the TTL
+            // index, which leaves its history behind.
+            Ok(_) => self.drop_history(&slug).await,
+            Err(e) if is_duplicate_key(&e) => Err(AppError::Conflict(format!("'{slug}' already exists"))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, slug: &str) -> Result<Option<SnippetRow>, AppError> {
+        Ok(self.col().find_one(doc! { "slug": slug }, None).await?)
+    }
+
+    async fn record_view(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError> {
+        let now = bson::DateTime::from_chrono(now);
+        let filter = doc! {
+            "slug": slug,
+            "expires_at": { "$gt": now },
+            "$or": [
+                { "max_views": null },
+                { "$expr": { "$lt": [{ "$ifNull": ["$views", 0] }, "$max_views"] } },
+            ],
+        };
+        let update = vec![
+            doc! { "$set": { "views": { "$add": [{ "$ifNull": ["$views", 0] }, 1] } } },
+            doc! { "$set": { "expires_at": { "$cond": [
+                { "$and": [{ "$isNumber": "$max_views" }, { "$gte": ["$views", "$max_views"] }] },
+                { "$min": ["$expires_at", now] },
+                "$expires_at",
+            ] } } },
+        ];
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        Ok(self.col().find_one_and_update(filter, update, opts).await?)
+    }
+
+    async fn patch(&self, slug: &str, patch: SnippetPatch) -> Result<Option<u64>, AppError> {
+        let mut filter = doc! { "slug": slug };
+        match patch.if_version {
+            // Rows written before versions existed have no field at all.
+            Some(0) => { filter.insert("version", doc! { "$in": [0_i64, bson::Bson::Null] }); }
+            Some(v) => { filter.insert("version", v as i64); }
+            None    => {}
+        }
+        let mut update = Document::new();
+        match patch.version {
+            Some(v) => { update.insert("$max", doc! { "version": v as i64 }); }
+            None if patch.touches_text() => { update.insert("$inc", doc! { "version": 1_i64 }); }
+            None => {}
+        }
+
+        let mut set = Document::new();
+        if let Some(c) = patch.content    { set.insert("content", c); }
+        if let Some(l) = patch.language   { set.insert("language", l); }
+        if let Some(i) = patch.images     { set.insert("images", to_bson(&i).unwrap()); }
+        if let Some(e) = patch.expires_at { set.insert("expires_at", bson::DateTime::from_chrono(e)); }
+        if let Some(c) = patch.crdt       { set.insert("crdt", bson::Binary { subtype: bson::spec::BinarySubtype::Generic, bytes: c }); }
+        if !set.is_empty() {
+            update.insert("$set", set);
+        }
+        if update.is_empty() {
+            return Ok(self.col().find_one(filter, None).await?.map(|r| r.version));
+        }
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let row = self.col().find_one_and_update(filter, update, opts).await?;
+        Ok(row.map(|r| r.version))
+    }
+
+    async fn delete(&self, slug: &str) -> Result<Option<SnippetRow>, AppError> {
+        let row = self.col().find_one_and_delete(doc! { "slug": slug }, None).await?;
+        if row.is_some() {
+            self.drop_history(slug).await?;
+        }
+        Ok(row)
+    }
+
+    async fn add_image(&self, slug: &str, image: ImageData) -> Result<bool, AppError> {
+        let id = image.id.clone();
+        self.push(slug, "images", &id, to_bson(&image).unwrap()).await
+    }
+
+    async fn remove_image(&self, slug: &str, id: &str) -> Result<bool, AppError> {
+        self.pull(slug, "images", id).await
+    }
+
+    async fn add_file(&self, slug: &str, file: FileData) -> Result<bool, AppError> {
+        let id = file.id.clone();
+        self.push(slug, "files", &id, to_bson(&file).unwrap()).await
+    }
+
+    async fn remove_file(&self, slug: &str, id: &str) -> Result<bool, AppError> {
+        self.pull(slug, "files", id).await
+    }
+
+    async fn expired(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<SnippetRow>, AppError> {
+        let opts = FindOptions::builder()
+            .sort(doc! { "expires_at": 1 })
+            .limit(limit as i64)
+            .build();
+        let cursor = self.col()
+            .find(doc! { "expires_at": { "$lte": bson::DateTime::from_chrono(now) } }, opts)
+            .await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn delete_if_expired(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError> {
+        let filter = doc! { "slug": slug, "expires_at": { "$lte": bson::DateTime::from_chrono(now) } };
+        let row = self.col().find_one_and_delete(filter, None).await?;
+        if row.is_some() {
+            self.drop_history(slug).await?;
+        }
+        Ok(row)
+    }
+
+    async fn record_revision(
+        &self,
+        slug: &str,
+        content: &str,
+        language: &str,
+        coalesce: chrono::Duration,
+    ) -> Result<Option<u64>, AppError> {
+        let kept = doc! { "slug": slug, "burn_after_reading": { "$ne": true } };
+        if self.col().count_documents(kept, None).await? == 0 {
+            return Ok(None);
+        }
+        let latest_first = FindOneOptions::builder().sort(doc! { "rev": -1 }).build();
+
+        // Two writers can pick the same next number; the unique index rejects
+        // the loser, which then re-reads and tries again.
+        for _ in 0..3 {
+            let now  = Utc::now();
+            let last = self.revs().find_one(doc! { "slug": slug }, latest_first.clone()).await?;
+            if let Some(last) = &last {
+                if last.content == content && last.language == language {
+                    return Ok(Some(last.rev as u64));
+                }
+                if now - last.created_at < coalesce {
+                    self.revs()
+                        .update_one(
+                            doc! { "slug": slug, "rev": last.rev },
+                            doc! { "$set": {
+                                "content":    content,
+                                "language":   language,
+                                "updated_at": bson::DateTime::from_chrono(now),
+                            } },
+                            None,
+                        )
+                        .await?;
+                    return Ok(Some(last.rev as u64));
+                }
+            }
+
+            let rev = last.map_or(1, |l| l.rev + 1);
+            let doc = RevisionDoc {
+                slug:       slug.to_string(),
+                rev,
+                content:    content.to_string(),
+                language:   language.to_string(),
+                created_at: now,
+                updated_at: now,
+            };
+            match self.revs().insert_one(doc, None).await {
+                Ok(_) => return Ok(Some(rev as u64)),
+                Err(e) if is_duplicate_key(&e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(AppError::Conflict("Too many concurrent revisions".into()))
+    }
+
+    async fn list_revisions(&self, slug: &str) -> Result<Vec<Revision>, AppError> {
+        let opts = FindOptions::builder().sort(doc! { "rev": -1 }).build();
+        let docs: Vec<RevisionDoc> = self.revs().find(doc! { "slug": slug }, opts).await?.try_collect().await?;
+        Ok(docs.into_iter().map(Into::into).collect())
+    }
+
+    async fn get_revision(&self, slug: &str, rev: u64) -> Result<Option<Revision>, AppError> {
+        let doc = self.revs().find_one(doc! { "slug": slug, "rev": rev as i64 }, None).await?;
+        Ok(doc.map(Into::into))
+    }
+
+    async fn add_chat(&self, msg: &ChatMessage, keep: usize) -> Result<(), AppError> {
+        // Numbering the message on the snippet also checks it still exists.
+        let opts = FindOneAndUpdateOptions::builder()
+            .projection(doc! { "chat_seq": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        let Some(counter) = self.db
+            .collection::<Document>("snippets")
+            .find_one_and_update(doc! { "slug": &msg.slug }, doc! { "$inc": { "chat_seq": 1_i64 } }, opts)
+            .await?
+        else {
+            return Ok(());
+        };
+        let doc = ChatDoc {
+            seq:     counter.get_i64("chat_seq").map_err(|e| AppError::Internal(e.to_string()))?,
+            id:      msg.id.clone(),
+            slug:    msg.slug.clone(),
+            conn:    msg.conn as i64,
+            name:    msg.name.clone(),
+            color:   msg.color.clone(),
+            text:    msg.text.clone(),
+            sent_at: msg.sent_at,
+        };
+        self.chat().insert_one(doc, None).await?;
+
+        // The first message past the newest `keep` marks where to cut.
+        let opts = FindOneOptions::builder().sort(doc! { "seq": -1 }).skip(keep as u64).build();
+        if let Some(cut) = self.chat().find_one(doc! { "slug": &msg.slug }, opts).await? {
+            self.chat().delete_many(doc! { "slug": &msg.slug, "seq": { "$lte": cut.seq } }, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_chat(&self, slug: &str, limit: usize) -> Result<Vec<ChatMessage>, AppError> {
+        let opts = FindOptions::builder().sort(doc! { "seq": -1 }).limit(limit as i64).build();
+        let docs: Vec<ChatDoc> = self.chat().find(doc! { "slug": slug }, opts).await?.try_collect().await?;
+        Ok(docs.into_iter().rev().map(Into::into).collect())
+    }
+
+    async fn issue_blob(&self, url: &str) -> Result<(), AppError> {
+        let blob = BlobDoc { url: url.to_string(), slug: None, issued_at: Utc::now() };
+        match self.blobs().insert_one(blob, None).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_key(&e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn claim_blob(&self, url: &str, slug: &str) -> Result<bool, AppError> {
+        let claimed = self.blobs()
+            .update_one(
+                doc! { "_id": url, "slug": { "$in": [null, slug] } },
+                doc! { "$set": { "slug": slug } },
+                None,
+            )
+            .await?;
+        if claimed.matched_count == 1 {
+            return Ok(true);
+        }
+        Ok(self.blobs().count_documents(doc! { "_id": url }, None).await? == 0)
+    }
+
+    async fn release_blobs(&self, slug: &str) -> Result<Vec<String>, AppError> {
+        let owned: Vec<BlobDoc> = self.blobs().find(doc! { "slug": slug }, None).await?.try_collect().await?;
+        let urls: Vec<String> = owned.into_iter().map(|b| b.url).collect();
+        self.blobs().delete_many(doc! { "_id": { "$in": &urls } }, None).await?;
+        Ok(urls)
+    }
+}
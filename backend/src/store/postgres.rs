@@ -0,0 +1,573 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use tracing::info;
+
+use super::{SnippetPatch, SnippetStore};
+use crate::chat::ChatMessage;
+use crate::error::AppError;
+use crate::revisions::Revision;
+use crate::{FileData, ImageData, SnippetRow};
+
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+#[derive(sqlx::FromRow)]
+struct SnippetRecord {
+    slug:       String,
+    content:    String,
+    language:   String,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    burn_after_reading: bool,
+    views:      i32,
+    max_views:  Option<i32>,
+    crdt:       Option<Vec<u8>>,
+    version:    i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct ImageRecord {
+    id:     String,
+    url:    String,
+    width:  i32,
+    height: i32,
+}
+
+#[derive(sqlx::FromRow)]
+struct FileRecord {
+    id:   String,
+    name: String,
+    url:  String,
+    size: i64,
+    mime: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct RevisionRecord {
+    slug:       String,
+    rev:        i64,
+    content:    String,
+    language:   String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ChatRecord {
+    id:      String,
+    slug:    String,
+    conn:    i64,
+    name:    String,
+    color:   String,
+    text:    String,
+    sent_at: DateTime<Utc>,
+}
+
+impl SnippetRecord {
+    fn into_row(self, images: Vec<ImageData>, files: Vec<FileData>) -> SnippetRow {
+        SnippetRow {
+            images,
+            files,
+            slug:       self.slug,
+            content:    self.content,
+            language:   self.language,
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            burn_after_reading: self.burn_after_reading,
+            views:      self.views as u32,
+            max_views:  self.max_views.map(|m| m as u32),
+            crdt:       self.crdt,
+            version:    self.version as u64,
+        }
+    }
+}
+
+impl From<ImageRecord> for ImageData {
+    fn from(r: ImageRecord) -> Self {
+        ImageData { id: r.id, url: r.url, width: r.width as u32, height: r.height as u32 }
+    }
+}
+
+impl From<FileRecord> for FileData {
+    fn from(r: FileRecord) -> Self {
+        FileData { id: r.id, name: r.name, url: r.url, size: r.size as u64, mime: r.mime }
+    }
+}
+
+impl From<RevisionRecord> for Revision {
+    fn from(r: RevisionRecord) -> Self {
+        Revision {
+            slug:       r.slug,
+            rev:        r.rev as u64,
+            content:    r.content,
+            language:   r.language,
+            created_at: r.created_at,
+            updated_at: r.updated_at,
+        }
+    }
+}
+
+impl From<ChatRecord> for ChatMessage {
+    fn from(r: ChatRecord) -> Self {
+        ChatMessage {
+            id:      r.id,
+            slug:    r.slug,
+            conn:    r.conn as u64,
+            name:    r.name,
+            color:   r.color,
+            text:    r.text,
+            sent_at: r.sent_at,
+        }
+    }
+}
+
+impl PostgresStore {
+    /// Connects and applies everything under `migrations/` before returning.
+    pub async fn connect(url: &str) -> Self {
+        let pool = PgPoolOptions::new()
+            .max_connections(10)
+            .connect(url)
+            .await
+            .expect("Failed to connect to Postgres");
+
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .expect("Failed to run Postgres migrations");
+
+        info!("Postgres migrations applied");
+        Self { pool }
+    }
+
+    /// Deletes the row, if `expired_by` is given only once it has expired by
+    /// then, and hands it back with its images and files. Those go with the
+    /// parent via ON DELETE CASCADE, so they are read first under a lock on
+    /// the parent that also keeps new ones from being attached.
+    async fn delete_row(&self, slug: &str, expired_by: Option<DateTime<Utc>>) -> Result<Option<SnippetRow>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let live: Option<String> = sqlx::query_scalar("SELECT slug FROM snippets WHERE slug = $1 FOR UPDATE")
+            .bind(slug)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if live.is_none() {
+            return Ok(None);
+        }
+        let images = images(&mut *tx, slug).await?;
+        let files  = files(&mut *tx, slug).await?;
+        let rec = sqlx::query_as::<_, SnippetRecord>(
+            "DELETE FROM snippets WHERE slug = $1 AND ($2::timestamptz IS NULL OR expires_at <= $2)
+             RETURNING slug, content, language, created_at, expires_at, burn_after_reading, views, max_views, crdt, version",
+        )
+        .bind(slug)
+        .bind(expired_by)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(rec.map(|rec| rec.into_row(images, files)))
+    }
+}
+
+async fn images<'e, E>(exec: E, slug: &str) -> Result<Vec<ImageData>, sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let rows = sqlx::query_as::<_, ImageRecord>(
+        "SELECT id, url, width, height FROM snippet_images WHERE slug = $1 ORDER BY seq",
+    )
+    .bind(slug)
+    .fetch_all(exec)
+    .await?;
+    Ok(rows.into_iter().map(Into::into).collect())
+}
+
+async fn files<'e, E>(exec: E, slug: &str) -> Result<Vec<FileData>, sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let rows = sqlx::query_as::<_, FileRecord>(
+        "SELECT id, name, url, size, mime FROM snippet_files WHERE slug = $1 ORDER BY seq",
+    )
+    .bind(slug)
+    .fetch_all(exec)
+    .await?;
+    Ok(rows.into_iter().map(Into::into).collect())
+}
+
+/// Inserts unless the snippet is missing or already has the id; `ON CONFLICT`
+/// makes that a single atomic statement. Returns whether a row went in.
+async fn insert_image<'e, E>(exec: E, slug: &str, image: &ImageData) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let res = sqlx::query(
+        "INSERT INTO snippet_images (slug, id, url, width, height)
+         SELECT $1, $2, $3, $4, $5 WHERE EXISTS (SELECT 1 FROM snippets WHERE slug = $1)
+         ON CONFLICT (slug, id) DO NOTHING",
+    )
+    .bind(slug)
+    .bind(&image.id)
+    .bind(&image.url)
+    .bind(image.width as i32)
+    .bind(image.height as i32)
+    .execute(exec)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+async fn insert_file<'e, E>(exec: E, slug: &str, file: &FileData) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let res = sqlx::query(
+        "INSERT INTO snippet_files (slug, id, name, url, size, mime)
+         SELECT $1, $2, $3, $4, $5, $6 WHERE EXISTS (SELECT 1 FROM snippets WHERE slug = $1)
+         ON CONFLICT (slug, id) DO NOTHING",
+    )
+    .bind(slug)
+    .bind(&file.id)
+    .bind(&file.name)
+    .bind(&file.url)
+    .bind(file.size as i64)
+    .bind(&file.mime)
+    .execute(exec)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+#[async_trait]
+impl SnippetStore for PostgresStore {
+    async fn exists(&self, slug: &str) -> Result<bool, AppError> {
+        let found: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM snippets WHERE slug = $1)")
+            .bind(slug)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(found)
+    }
+
+    async fn create(&self, row: SnippetRow) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let inserted = sqlx::query(
+            "INSERT INTO snippets
+                 (slug, content, language, created_at, expires_at, burn_after_reading, views, max_views)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             ON CONFLICT (slug) DO NOTHING",
+        )
+        .bind(&row.slug)
+        .bind(&row.content)
+        .bind(&row.language)
+        .bind(row.created_at)
+        .bind(row.expires_at)
+        .bind(row.burn_after_reading)
+        .bind(row.views as i32)
+        .bind(row.max_views.map(|m| m as i32))
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if inserted == 0 {
+            return Err(AppError::Conflict(format!("'{}' already exists", row.slug)));
+        }
+        for image in &row.images {
+            insert_image(&mut *tx, &row.slug, image).await?;
+        }
+        for file in &row.files {
+            insert_file(&mut *tx, &row.slug, file).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get(&self, slug: &str) -> Result<Option<SnippetRow>, AppError> {
+        let rec = sqlx::query_as::<_, SnippetRecord>(
+            "SELECT slug, content, language, created_at, expires_at, burn_after_reading, views, max_views, crdt, version
+             FROM snippets WHERE slug = $1",
+        )
+        .bind(slug)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(rec) = rec else { return Ok(None) };
+        let images = images(&self.pool, slug).await?;
+        let files  = files(&self.pool, slug).await?;
+        Ok(Some(rec.into_row(images, files)))
+    }
+
+    async fn record_view(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError> {
+        let counted = sqlx::query(
+            "UPDATE snippets
+             SET views      = views + 1,
+                 expires_at = CASE WHEN max_views IS NOT NULL AND views + 1 >= max_views
+                                   THEN LEAST(expires_at, $2) ELSE expires_at END
+             WHERE slug = $1
+               AND expires_at > $2
+               AND (max_views IS NULL OR views < max_views)",
+        )
+        .bind(slug)
+        .bind(now)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        if counted == 0 {
+            return Ok(None);
+        }
+        self.get(slug).await
+    }
+
+    async fn patch(&self, slug: &str, patch: SnippetPatch) -> Result<Option<u64>, AppError> {
+        let bump = patch.touches_text();
+        let mut tx = self.pool.begin().await?;
+        let version: Option<i64> = sqlx::query_scalar(
+            "UPDATE snippets
+             SET content    = COALESCE($2, content),
+                 language   = COALESCE($3, language),
+                 expires_at = COALESCE($4, expires_at),
+                 crdt       = COALESCE($5, crdt),
+                 version    = CASE WHEN $6::BIGINT IS NOT NULL THEN GREATEST(version, $6)
+                                   WHEN $7 THEN version + 1
+                                   ELSE version END
+             WHERE slug = $1 AND ($8::BIGINT IS NULL OR version = $8)
+             RETURNING version",
+        )
+        .bind(slug)
+        .bind(patch.content)
+        .bind(patch.language)
+        .bind(patch.expires_at)
+        .bind(patch.crdt)
+        .bind(patch.version.map(|v| v as i64))
+        .bind(bump)
+        .bind(patch.if_version.map(|v| v as i64))
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(version) = version else { return Ok(None) };
+
+        if let Some(images) = patch.images {
+            sqlx::query("DELETE FROM snippet_images WHERE slug = $1")
+                .bind(slug)
+                .execute(&mut *tx)
+                .await?;
+            for image in &images {
+                insert_image(&mut *tx, slug, image).await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(Some(version as u64))
+    }
+
+    async fn delete(&self, slug: &str) -> Result<Option<SnippetRow>, AppError> {
+        self.delete_row(slug, None).await
+    }
+
+    async fn add_image(&self, slug: &str, image: ImageData) -> Result<bool, AppError> {
+        Ok(insert_image(&self.pool, slug, &image).await?)
+    }
+
+    async fn remove_image(&self, slug: &str, id: &str) -> Result<bool, AppError> {
+        let removed = sqlx::query("DELETE FROM snippet_images WHERE slug = $1 AND id = $2")
+            .bind(slug)
+            .bind(id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(removed > 0)
+    }
+
+    async fn add_file(&self, slug: &str, file: FileData) -> Result<bool, AppError> {
+        Ok(insert_file(&self.pool, slug, &file).await?)
+    }
+
+    async fn remove_file(&self, slug: &str, id: &str) -> Result<bool, AppError> {
+        let removed = sqlx::query("DELETE FROM snippet_files WHERE slug = $1 AND id = $2")
+            .bind(slug)
+            .bind(id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(removed > 0)
+    }
+
+    async fn expired(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<SnippetRow>, AppError> {
+        let slugs: Vec<String> = sqlx::query_scalar(
+            "SELECT slug FROM snippets WHERE expires_at <= $1 ORDER BY expires_at LIMIT $2",
+        )
+        .bind(now)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut rows = Vec::with_capacity(slugs.len());
+        for slug in slugs {
+            if let Some(row) = self.get(&slug).await? { rows.push(row); }
+        }
+        Ok(rows)
+    }
+
+    async fn delete_if_expired(&self, slug: &str, now: DateTime<Utc>) -> Result<Option<SnippetRow>, AppError> {
+        self.delete_row(slug, Some(now)).await
+    }
+
+    async fn record_revision(
+        &self,
+        slug: &str,
+        content: &str,
+        language: &str,
+        coalesce: chrono::Duration,
+    ) -> Result<Option<u64>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        // Locking the parent row serialises writers on the same slug, so the
+        // next revision number cannot be taken twice.
+        let live: Option<String> = sqlx::query_scalar(
+            "SELECT slug FROM snippets WHERE slug = $1 AND NOT burn_after_reading FOR UPDATE",
+        )
+        .bind(slug)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if live.is_none() {
+            return Ok(None);
+        }
+
+        let now  = Utc::now();
+        let last = sqlx::query_as::<_, RevisionRecord>(
+            "SELECT slug, rev, content, language, created_at, updated_at
+             FROM snippet_revisions WHERE slug = $1 ORDER BY rev DESC LIMIT 1",
+        )
+        .bind(slug)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(last) = &last {
+            if last.content == content && last.language == language {
+                return Ok(Some(last.rev as u64));
+            }
+            if now - last.created_at < coalesce {
+                sqlx::query(
+                    "UPDATE snippet_revisions SET content = $3, language = $4, updated_at = $5
+                     WHERE slug = $1 AND rev = $2",
+                )
+                .bind(slug)
+                .bind(last.rev)
+                .bind(content)
+                .bind(language)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                return Ok(Some(last.rev as u64));
+            }
+        }
+
+        let rev = last.map_or(1, |l| l.rev + 1);
+        sqlx::query(
+            "INSERT INTO snippet_revisions (slug, rev, content, language, created_at, updated_at)
+             VALUES ($1, $2, $3, $4, $5, $5)",
+        )
+        .bind(slug)
+        .bind(rev)
+        .bind(content)
+        .bind(language)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(rev as u64))
+    }
+
+    async fn list_revisions(&self, slug: &str) -> Result<Vec<Revision>, AppError> {
+        let rows = sqlx::query_as::<_, RevisionRecord>(
+            "SELECT slug, rev, content, language, created_at, updated_at
+             FROM snippet_revisions WHERE slug = $1 ORDER BY rev DESC",
+        )
+        .bind(slug)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    async fn get_revision(&self, slug: &str, rev: u64) -> Result<Option<Revision>, AppError> {
+        let row = sqlx::query_as::<_, RevisionRecord>(
+            "SELECT slug, rev, content, language, created_at, updated_at
+             FROM snippet_revisions WHERE slug = $1 AND rev = $2",
+        )
+        .bind(slug)
+        .bind(rev as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Into::into))
+    }
+
+    async fn add_chat(&self, msg: &ChatMessage, keep: usize) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO snippet_chat (slug, id, conn, name, color, text, sent_at)
+             SELECT $1, $2, $3, $4, $5, $6, $7 WHERE EXISTS (SELECT 1 FROM snippets WHERE slug = $1)",
+        )
+        .bind(&msg.slug)
+        .bind(&msg.id)
+        .bind(msg.conn as i64)
+        .bind(&msg.name)
+        .bind(&msg.color)
+        .bind(&msg.text)
+        .bind(msg.sent_at)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "DELETE FROM snippet_chat WHERE slug = $1 AND seq <= (
+                 SELECT seq FROM snippet_chat WHERE slug = $1 ORDER BY seq DESC OFFSET $2 LIMIT 1
+             )",
+        )
+        .bind(&msg.slug)
+        .bind(keep as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_chat(&self, slug: &str, limit: usize) -> Result<Vec<ChatMessage>, AppError> {
+        let mut rows = sqlx::query_as::<_, ChatRecord>(
+            "SELECT id, slug, conn, name, color, text, sent_at
+             FROM snippet_chat WHERE slug = $1 ORDER BY seq DESC LIMIT $2",
+        )
+        .bind(slug)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.reverse();
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    async fn issue_blob(&self, url: &str) -> Result<(), AppError> {
+        sqlx::query("INSERT INTO blobs (url) VALUES ($1) ON CONFLICT (url) DO NOTHING")
+            .bind(url)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_blob(&self, url: &str, slug: &str) -> Result<bool, AppError> {
+        let claimed = sqlx::query("UPDATE blobs SET slug = $2 WHERE url = $1 AND (slug IS NULL OR slug = $2)")
+            .bind(url)
+            .bind(slug)
+            .execute(&self.pool)
+            .await?;
+        if claimed.rows_affected() == 1 {
+            return Ok(true);
+        }
+        let issued: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM blobs WHERE url = $1)")
+            .bind(url)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(!issued)
+    }
+
+    async fn release_blobs(&self, slug: &str) -> Result<Vec<String>, AppError> {
+        Ok(sqlx::query_scalar("DELETE FROM blobs WHERE slug = $1 RETURNING url")
+            .bind(slug)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+}